@@ -1,7 +1,63 @@
+use std::time::Duration;
+
 use mio::Token;
 
 pub const OUTPUT_BUF_SIZE: usize = 1024;
 pub const INPUT_BUF_SIZE: usize = 1024;
+// Largest encoded message sent to clients
+pub const MESSAGE_BUF_SIZE: usize = 4096;
 pub const TCP_LISTENER_TOKEN: Token = Token(0);
 pub const WS_LISTENER_TOKEN: Token = Token(1);
+pub const WAKER_TOKEN: Token = Token(2);
 pub const EVENT_CAPACITY: usize = 128;
+pub const OUTPUT_THREADS: usize = 4;
+// Threads writing client sockets
+pub const CLIENT_THREADS: usize = 2;
+// Price levels on each side of depth
+pub const DEPTH_LEVELS: usize = 5;
+pub const MAX_CLIENT_SUBSCRIPTIONS: usize = 5000;
+pub const PROTOCOL_VERSION: u16 = 1;
+// Size of message field in native error response
+pub const ERROR_MESSAGE_SIZE: usize = 62;
+// Token results per acknowledgement message, larger batches are split
+pub const ACK_ENTRIES_PER_MESSAGE: usize = 32;
+// Market messages replayed to clients opting in at init
+pub const MARKET_HISTORY_SIZE: usize = 64;
+
+// Feed packet types
+pub const FEED_DEPTH: u16 = 1;
+pub const FEED_TOUCH_LINE: u16 = 2;
+pub const FEED_MINI_TOUCH_LINE: u16 = 3;
+
+pub const KAFKA_GROUP_ID: &str = "feed_distributor";
+pub const KAFKA_POLL_TIMEOUT: Duration = Duration::from_millis(100);
+pub const KAFKA_METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Websocket connections stuck in these states are dropped
+pub const WS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const WS_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// Clients disconnected with an error are dropped if they don't read it in time
+pub const DISCONNECT_ERROR_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Feed input backs off from this delay up to the max while recv keeps failing
+pub const FEED_ERROR_DELAY: Duration = Duration::from_millis(10);
+pub const FEED_ERROR_MAX_DELAY: Duration = Duration::from_secs(1);
+
+pub const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+
+pub const DISTRIBUTOR_READ_TIMEOUT: Duration = Duration::from_millis(10);
+pub const DISTRIBUTOR_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Client request types
+pub const INIT_REQUEST: u16 = 1;
+pub const SUBSCRIBE_REQUEST: u16 = 2;
+pub const UNSUBSCRIBE_REQUEST: u16 = 3;
+pub const UDP_SWITCH_REQUEST: u16 = 4;
+
+// Server message types
+pub const INIT_RESPONSE: u16 = 100;
+pub const UPDATE_MESSAGE: u16 = 101;
+pub const SNAPSHOT_MESSAGE: u16 = 102;
+pub const ERROR_RESPONSE: u16 = 103;
+pub const ACK_RESPONSE: u16 = 104;
+pub const MARKET_MESSAGE: u16 = 105;
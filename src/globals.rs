@@ -1,34 +1,78 @@
 use crate::{
-    create_array,
+    threadpool::work_queue::WorkQueue,
     types::{
         client_profile::ClientProfile,
-        keep_latest::KeepLatest,
+        market_data::{MarketData, TokenData},
         packet::OutputPacket,
-        reuse_array::ReuseArr,
+        reuse_array::{Handle, ReuseArr},
         settings::{self, Mode, Settings},
+        subscription::{Subscription, UpstreamRequest},
+        token_universe::TokenUniverse,
+        work::{ClientWork, FeedWork},
     },
 };
 use crossbeam::queue::SegQueue;
 use lazy_static::lazy_static;
-use std::sync::OnceLock;
+use mio::{Registry, Waker};
+use std::{
+    collections::VecDeque,
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+};
 
 pub static SETTINGS: OnceLock<Settings> = OnceLock::new();
-pub static DATA_STORE: [KeepLatest<usize>; 35000] = create_array!(KeepLatest::new(); 35000);
-pub static TOKEN_PACKETS_QUEUE: [SegQueue<OutputPacket>; 35000] = create_array!(SegQueue::new(); 35000);
 pub static MARKET_MESSAGES_QUEUE: SegQueue<OutputPacket> = SegQueue::new();
+// Set while market messages are scheduled or being broadcast
+pub static MARKET_LOCK: AtomicBool = AtomicBool::new(false);
+// Last market messages, replayed to clients joining later
+pub static MARKET_HISTORY: Mutex<VecDeque<OutputPacket>> = Mutex::new(VecDeque::new());
+pub static SESSION_COUNTER: AtomicU64 = AtomicU64::new(1);
+pub static UPSTREAM_REQUESTS: SegQueue<UpstreamRequest> = SegQueue::new();
+// Registry of client poll, used to toggle writable interest from any thread
+pub static REGISTRY: OnceLock<Registry> = OnceLock::new();
+// Wakes up client poll to watch deadlines of clients set by other threads
+pub static WAKER: OnceLock<Waker> = OnceLock::new();
+pub static DEADLINE_CLIENTS: SegQueue<Handle> = SegQueue::new();
 
 lazy_static! {
-    pub static ref MODE: Mode = settings::get().mode.clone();
+    pub static ref MODE: Mode = settings::get().mode;
     pub static ref CLIENTS_LIST: ReuseArr<ClientProfile> = ReuseArr::new();
+    pub static ref UDP_SOCKET: UdpSocket = {
+        let socket = UdpSocket::bind(&settings::get().udp_output_address).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket
+    };
+    pub static ref FEED_WORK_QUEUE: Arc<WorkQueue<FeedWork>> = Arc::new(WorkQueue::new());
+    pub static ref CLIENT_WORK_QUEUE: Arc<WorkQueue<ClientWork>> = Arc::new(WorkQueue::new());
+    // Maps exchange tokens to slots of token wise globals below
+    pub static ref TOKENS: TokenUniverse = TokenUniverse::from_settings(&settings::get().tokens);
+    // Latest value of each data type for every token
+    pub static ref DATA_STORE: Box<[TokenData]> = (0..TOKENS.len()).map(|_| TokenData::new()).collect();
+    pub static ref TOKEN_PACKETS_QUEUE: Box<[SegQueue<MarketData>]> =
+        (0..TOKENS.len()).map(|_| SegQueue::new()).collect();
+    // Set while a token is scheduled or being dispatched
+    pub static ref TOKEN_LOCKS: Box<[AtomicBool]> = (0..TOKENS.len()).map(|_| AtomicBool::new(false)).collect();
+    pub static ref SUBSCRIPTIONS: Box<[RwLock<Subscription>]> =
+        (0..TOKENS.len()).map(|_| RwLock::new(Subscription::new())).collect();
 }
 
 pub fn init() {
     let args = std::env::args().collect::<Vec<String>>();
     let settings_path = args.get(1).expect("Settings path not provided");
 
-    // Skip first 2 index
+    // Slots of listener and waker tokens, they hold no client so iteration skips them
+    CLIENTS_LIST.reserve();
     CLIENTS_LIST.reserve();
     CLIENTS_LIST.reserve();
 
     settings::init(settings_path);
+
+    // Allocate token wise globals before feed starts
+    lazy_static::initialize(&DATA_STORE);
+    lazy_static::initialize(&TOKEN_PACKETS_QUEUE);
+    lazy_static::initialize(&TOKEN_LOCKS);
+    lazy_static::initialize(&SUBSCRIPTIONS);
 }
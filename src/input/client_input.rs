@@ -1,29 +1,60 @@
 use std::{
-    io::{Read, Write},
-    net::Shutdown,
-    sync::Arc,
+    collections::HashSet,
+    io::Read,
+    mem::{self, offset_of, size_of},
+    net::{Shutdown, SocketAddr},
+    sync::{atomic::Ordering, PoisonError, RwLock},
+    time::Instant,
 };
 
 use mio::{
     net::{TcpListener, TcpStream},
-    Events, Interest, Poll, Token,
+    Events, Interest, Poll, Token, Waker,
 };
-use tungstenite::{accept, handshake::server::NoCallback, HandshakeError, ServerHandshake, WebSocket};
+use tungstenite::{handshake::server::NoCallback, HandshakeError, Message, ServerHandshake};
 
 use crate::{
-    constants::{EVENT_CAPACITY, TCP_LISTENER_TOKEN, WS_LISTENER_TOKEN},
-    globals::CLIENTS_LIST,
+    constants::{
+        ACK_ENTRIES_PER_MESSAGE, ACK_RESPONSE, ERROR_RESPONSE, EVENT_CAPACITY, INIT_REQUEST, INIT_RESPONSE,
+        INPUT_BUF_SIZE, MAX_CLIENT_SUBSCRIPTIONS, MESSAGE_BUF_SIZE, PROTOCOL_VERSION, SNAPSHOT_MESSAGE,
+        SUBSCRIBE_REQUEST, TCP_LISTENER_TOKEN, UDP_SWITCH_REQUEST, UNSUBSCRIBE_REQUEST, WAKER_TOKEN, WS_CLOSE_TIMEOUT,
+        WS_HANDSHAKE_TIMEOUT, WS_LISTENER_TOKEN,
+    },
+    globals::{
+        CLIENTS_LIST, DATA_STORE, DEADLINE_CLIENTS, REGISTRY, SESSION_COUNTER, SUBSCRIPTIONS, TOKENS,
+        UPSTREAM_REQUESTS, WAKER,
+    },
+    output::{
+        disconnect_with_error,
+        encoder::{encode, encode_ack, encode_update, JsonMessage, UpdateMessage},
+        market::join_market_messages,
+        send_data,
+    },
+    threadpool::client_threadpool::ClientThreadpool,
     types::{
-        client_profile::{ClientProfile, Connection},
+        client_profile::{ClientProfile, ClientSubscription, Connection, TypeFlags, WsConnection},
         packet::InputPacket,
-        settings,
+        protocol::{
+            DataType, ErrorCode, ErrorResponse, InitRequest, InitResponse, MessageHeader, NativeInitRequest,
+            NativeUdpSwitchRequest, Request, TokenRequest, TokenResult,
+        },
+        reuse_array::Handle,
+        settings::{self, Mode, Role},
+        subscription::{Subscription, UpstreamRequest},
+    },
+    utils::{
+        byte_utils::{bytes_to_partial_struct, bytes_to_struct, create_empty},
+        error_utils::{interrupted, would_block},
     },
-    utils::error_utils::{interrupted, would_block},
 };
 
+const HEADER_SIZE: usize = size_of::<MessageHeader>();
+
 pub struct ClientInput {
     listeners: [TcpListener; 2],
     poll: Poll,
+    // Clients which can time out, checked after every poll
+    deadlines: HashSet<Handle>,
 }
 
 impl ClientInput {
@@ -44,9 +75,14 @@ impl ClientInput {
             .register(&mut ws_listener, WS_LISTENER_TOKEN, Interest::READABLE)
             .unwrap();
 
+        // Allow output threads to watch client sockets for writable events
+        let _ = REGISTRY.set(poll.registry().try_clone().unwrap());
+        let _ = WAKER.set(Waker::new(poll.registry(), WAKER_TOKEN).unwrap());
+
         Self {
             listeners: [tcp_listener, ws_listener],
             poll,
+            deadlines: HashSet::new(),
         }
     }
 
@@ -54,8 +90,17 @@ impl ClientInput {
         loop {
             let mut events = Events::with_capacity(EVENT_CAPACITY);
 
+            // Wake up for nearest deadline
+            let now = Instant::now();
+            let timeout = self
+                .deadlines
+                .iter()
+                .filter_map(|handle| client_deadline(*handle))
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now));
+
             // Load all events
-            if let Err(err) = self.poll.poll(&mut events, None) {
+            if let Err(err) = self.poll.poll(&mut events, timeout) {
                 if interrupted(&err) {
                     continue;
                 }
@@ -70,136 +115,843 @@ impl ClientInput {
                         // Continuosly accept new connections
                         match self.listeners[event_token.0].accept() {
                             // Handle accepted connection
-                            Ok((stream, _)) => handle_connection(stream, event_token, &self.poll),
+                            Ok((stream, _)) => {
+                                if let Some(handle) = handle_connection(stream, event_token, &self.poll) {
+                                    self.track_deadline(handle);
+                                }
+                            }
                             // Wait for more connections
                             Err(e) if interrupted(&e) => continue,
                             // No more connections
                             _ => break,
                         }
                     },
+                    // Other threads set deadlines of these clients
+                    WAKER_TOKEN => {
+                        while let Some(handle) = DEADLINE_CLIENTS.pop() {
+                            self.track_deadline(handle);
+                        }
+                    }
                     token => {
                         // For other events
-                        handle_request(token.0);
+                        let handle = Handle::from(token.0);
+
+                        handle_request(handle);
+                        self.track_deadline(handle);
                     }
                 }
             }
+
+            self.expire_connections();
         }
     }
-}
 
-fn handshake_ws(
-    stream: TcpStream,
-) -> Result<WebSocket<TcpStream>, HandshakeError<ServerHandshake<TcpStream, NoCallback>>> {
-    match accept(stream) {
-        Ok(ws) => Ok(ws),
-        Err(HandshakeError::Interrupted(mut mid)) => loop {
-            // Interrupted while doing handshake
-            // Try handshake till error or success
-            match mid.handshake() {
-                Ok(ws) => break Ok(ws),
-                Err(HandshakeError::Interrupted(m)) => {
-                    mid = m;
-                    continue;
-                }
-                Err(e) => return Err(e),
+    fn track_deadline(&mut self, handle: Handle) {
+        if client_deadline(handle).is_some() {
+            self.deadlines.insert(handle);
+        }
+    }
+
+    // Drop websocket connections stuck in handshake or closing
+    fn expire_connections(&mut self) {
+        let now = Instant::now();
+
+        self.deadlines.retain(|handle| match client_deadline(*handle) {
+            Some(deadline) if deadline <= now => {
+                handle_disconnection(*handle);
+                false
             }
-        },
-        Err(e) => Err(e),
+            Some(_) => true,
+            None => false,
+        });
     }
 }
 
-pub fn handle_connection(mut stream: TcpStream, event_token: Token, poll: &Poll) {
-    // Reserve index
-    let idx = CLIENTS_LIST.reserve();
+fn client_deadline(handle: Handle) -> Option<Instant> {
+    CLIENTS_LIST
+        .get(handle)
+        .and_then(|client_profile| client_profile.deadline())
+}
+
+// Returns handle of new client, None if it could not be registered
+pub fn handle_connection(mut stream: TcpStream, event_token: Token, poll: &Poll) -> Option<Handle> {
+    // Reserve slot
+    let handle = CLIENTS_LIST.reserve();
 
-    // Register stream with idx as identifier
-    poll.registry()
-        .register(&mut stream, Token(idx), Interest::READABLE)
-        .unwrap();
+    // Websocket handshake needs writable events to send its response
+    let interest = match event_token {
+        WS_LISTENER_TOKEN => Interest::READABLE | Interest::WRITABLE,
+        _ => Interest::READABLE,
+    };
 
-    // Create connection
+    // Register stream with handle as identifier
+    if poll
+        .registry()
+        .register(&mut stream, Token(handle.into()), interest)
+        .is_err()
+    {
+        // Release reserved slot
+        CLIENTS_LIST.remove(handle);
+        return None;
+    }
+
+    // Create connection, websocket upgrade is driven by poll events
     let conn = match event_token {
-        TCP_LISTENER_TOKEN => Connection::Tcp(stream),
-        WS_LISTENER_TOKEN => match handshake_ws(stream) {
-            Ok(conn) => Connection::Ws(conn),
-            Err(_) => {
-                handle_disconnection(idx);
-                return;
-            }
-        },
-        _ => unreachable!(),
+        WS_LISTENER_TOKEN => {
+            let handshake = ServerHandshake::start(stream, NoCallback, None);
+
+            Connection::Ws(Box::new(WsConnection::Handshake(
+                handshake,
+                Instant::now() + WS_HANDSHAKE_TIMEOUT,
+            )))
+        }
+        _ => Connection::Tcp(stream),
     };
 
     println!("Connected");
     // Create client profile and insert it
-    CLIENTS_LIST.insert_at(ClientProfile::create_empty(Arc::new(conn)), idx);
+    CLIENTS_LIST.insert_at(ClientProfile::create_empty(conn, handle), handle);
+
+    Some(handle)
 }
 
-pub fn handle_request(idx: usize) {
-    let mut packet = InputPacket::new();
+pub fn handle_request(handle: Handle) {
+    let mut requests = Vec::new();
 
-    let client_profile = CLIENTS_LIST.get_mut(idx).as_mut().unwrap();
+    // Events can arrive for already disconnected clients
+    let Some(client_profile) = CLIENTS_LIST.get(handle) else {
+        return;
+    };
 
-    if let Connection::Tcp(stream) = &mut client_profile.conn {
-        loop {
-            match stream.read(&mut packet.0) {
-                Ok(0) => {
-                    // Connection closed
-                    handle_disconnection(idx);
-                    return;
-                }
-                Ok(size) => {
-                    // Read data
-                    packet.1 += size;
-                    continue;
+    // Websocket state is shared with threads writing to client
+    let mut conn = client_profile.conn();
+
+    let result = match &mut *conn {
+        Connection::Tcp(stream) => {
+            let mut packet = client_profile.input.lock().unwrap_or_else(PoisonError::into_inner);
+
+            read_tcp(stream, &mut packet, &mut requests)
+        }
+        Connection::Ws(ws) => Ok(read_ws(handle, ws, &mut requests)),
+    };
+
+    // Handlers and disconnection lock connection again
+    drop(conn);
+
+    match result {
+        Ok(true) => {}
+        Ok(false) => {
+            handle_disconnection(handle);
+            return;
+        }
+        // Rest of stream can not be framed, read side EOF completes disconnection after error
+        Err(request_id) => {
+            if disconnect_with_error(&client_profile, request_id, ErrorCode::MalformedRequest).is_err() {
+                handle_disconnection(handle);
+            }
+            return;
+        }
+    }
+
+    for (request_id, request) in requests {
+        // Stop if client got disconnected by previous request
+        let Some(client_profile) = CLIENTS_LIST.get(handle) else {
+            return;
+        };
+
+        match request {
+            Request::Init(init) => handle_init(handle, request_id, init),
+            Request::Invalid(code) => handle_invalid_request(handle, request_id, code),
+            // Everything else requires an initialized session
+            _ if !client_profile.session().initialized => {
+                handle_invalid_request(handle, request_id, ErrorCode::NotInitialized)
+            }
+            Request::Subscribe(subscriptions) => handle_token_subscribe(handle, request_id, subscriptions),
+            Request::Unsubscribe(subscriptions) => handle_token_unsubscribe(handle, request_id, subscriptions),
+            Request::UdpSwitch(address) => handle_udp_switch(handle, request_id, address),
+        }
+    }
+
+    // Write whatever is left in queue, also handles writable events
+    ClientThreadpool::flush(handle);
+}
+
+// Read until socket would block and collect received requests
+// Returns false if connection is closed, framing error is returned with id of offending request
+fn read_tcp(stream: &mut TcpStream, packet: &mut InputPacket, requests: &mut Vec<(u32, Request)>) -> Result<bool, u32> {
+    loop {
+        match stream.read(&mut packet.0[packet.1..]) {
+            // Connection closed
+            Ok(0) => return Ok(false),
+            Ok(size) => {
+                // Read data
+                packet.1 += size;
+
+                // Extract complete requests to make space for more data
+                parse_requests(packet, requests)?;
+            }
+            // Waiting for more data to read from socket
+            Err(e) if interrupted(&e) => continue,
+            // No more data available
+            Err(e) if would_block(&e) => return Ok(true),
+            // Something else went wrong
+            Err(_) => return Ok(false),
+        }
+    }
+}
+
+// Advance websocket connection as far as socket allows and collect received requests
+// Returns false if connection is finished
+fn read_ws(handle: Handle, ws: &mut WsConnection, requests: &mut Vec<(u32, Request)>) -> bool {
+    'state: loop {
+        match mem::replace(ws, WsConnection::Closed) {
+            WsConnection::Handshake(handshake, deadline) => match handshake.handshake() {
+                // Requests can be buffered already, read them
+                Ok(mut socket) => {
+                    // Writable events are needed again only when output is pending
+                    if let Some(registry) = REGISTRY.get() {
+                        if registry
+                            .reregister(socket.get_mut(), Token(handle.into()), Interest::READABLE)
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+
+                    *ws = WsConnection::Open(socket);
                 }
-                Err(e) if interrupted(&e) => {
-                    // Waiting for more data to read from socket
-                    continue;
+                Err(HandshakeError::Interrupted(handshake)) => {
+                    *ws = WsConnection::Handshake(handshake, deadline);
+                    return true;
                 }
-                Err(e) if would_block(&e) => {
-                    // No more data available
-                    break;
+                Err(HandshakeError::Failure(_)) => break,
+            },
+            WsConnection::Open(mut socket) => {
+                // Send frames queued while socket was full, errors show up on read
+                let _ = socket.flush();
+
+                loop {
+                    match socket.read() {
+                        Ok(Message::Text(text)) => requests.push(Request::from_json(text.as_bytes())),
+                        Ok(Message::Binary(data)) => requests.push(Request::from_json(&data)),
+                        Ok(Message::Close(_)) => {
+                            // Reply is queued by websocket, wait for it to be sent
+                            *ws = WsConnection::Closing(socket, Instant::now() + WS_CLOSE_TIMEOUT);
+                            continue 'state;
+                        }
+                        // Ping and pong are answered by websocket
+                        Ok(_) => continue,
+                        Err(tungstenite::Error::Io(e)) if interrupted(&e) => continue,
+                        Err(tungstenite::Error::Io(e)) if would_block(&e) => {
+                            *ws = WsConnection::Open(socket);
+                            return true;
+                        }
+                        Err(_) => break 'state,
+                    }
                 }
-                Err(_) => {
-                    // Something else went wrong
-                    // Disconnect
-                    handle_disconnection(idx);
-                    return;
+            }
+            WsConnection::Closing(mut socket, deadline) => loop {
+                // Reading flushes close reply, closed error means close handshake is done
+                match socket.read() {
+                    Ok(_) => continue,
+                    Err(tungstenite::Error::Io(e)) if interrupted(&e) => continue,
+                    Err(tungstenite::Error::Io(e)) if would_block(&e) => {
+                        *ws = WsConnection::Closing(socket, deadline);
+                        return true;
+                    }
+                    Err(_) => break 'state,
                 }
-            };
+            },
+            WsConnection::Closed => break,
         }
+    }
+
+    false
+}
+
+// Decode all complete messages in packet and keep remaining partial message
+// Returns id of offending request if framing is invalid
+fn parse_requests(packet: &mut InputPacket, requests: &mut Vec<(u32, Request)>) -> Result<(), u32> {
+    let mut offset = 0;
 
-        // println!("Received {:?}", String::from_utf8_lossy(&buffer[..sz]));
+    while packet.1 - offset >= HEADER_SIZE {
+        let header: MessageHeader = bytes_to_struct(&packet.0[offset..]);
+        let length = header.length as usize;
+
+        // Message can never fit in buffer
+        if HEADER_SIZE + length > INPUT_BUF_SIZE {
+            return Err(header.request_id);
+        }
+
+        // Wait for rest of message
+        if packet.1 - offset < HEADER_SIZE + length {
+            break;
+        }
 
-        stream.write_all(b"Hello").unwrap();
-        stream.flush().unwrap();
-    } else if let Connection::Ws(ws) = &mut client_profile.conn {
-        let msg = ws.read().unwrap();
+        let payload = &packet.0[offset + HEADER_SIZE..offset + HEADER_SIZE + length];
+        requests.push((header.request_id, decode_request(header.msg_type, payload)));
 
-        ws.send(msg);
-        // println!("Received {:?}", msg);
+        offset += HEADER_SIZE + length;
     }
+
+    // Move partial message to start
+    packet.0.copy_within(offset..packet.1, 0);
+    packet.1 -= offset;
+
+    Ok(())
 }
 
-pub fn handle_init() {}
+fn decode_request(msg_type: u16, payload: &[u8]) -> Request {
+    let result = match msg_type {
+        // Flags are optional
+        INIT_REQUEST
+            if payload.len() == size_of::<NativeInitRequest>()
+                || payload.len() == offset_of!(NativeInitRequest, flags) =>
+        {
+            let mut init: NativeInitRequest = create_empty();
+            bytes_to_partial_struct(&mut init, payload);
+
+            init.decode().map(Request::Init).ok_or(ErrorCode::MalformedRequest)
+        }
+        SUBSCRIBE_REQUEST => decode_token_requests(payload).map(Request::Subscribe),
+        UNSUBSCRIBE_REQUEST => decode_token_requests(payload).map(Request::Unsubscribe),
+        UDP_SWITCH_REQUEST if payload.len() == size_of::<NativeUdpSwitchRequest>() => {
+            let udp_switch: NativeUdpSwitchRequest = bytes_to_struct(payload);
 
-pub fn handle_token_subscribe() {}
+            Ok(Request::UdpSwitch(udp_switch.decode()))
+        }
+        // Known request with wrong payload size
+        INIT_REQUEST | UDP_SWITCH_REQUEST => Err(ErrorCode::MalformedRequest),
+        _ => Err(ErrorCode::UnknownRequest),
+    };
 
-pub fn handle_token_unsubscribe() {}
+    result.unwrap_or_else(Request::Invalid)
+}
 
-pub fn handle_udp_switch() {}
+fn decode_token_requests(payload: &[u8]) -> Result<Vec<ClientSubscription>, ErrorCode> {
+    let entry_size = size_of::<TokenRequest>();
 
-pub fn handle_invalid_request() {}
+    if !payload.len().is_multiple_of(entry_size) {
+        return Err(ErrorCode::MalformedRequest);
+    }
 
-pub fn handle_disconnection(idx: usize) {
-    let client_profile = CLIENTS_LIST.remove(idx).unwrap();
+    payload
+        .chunks(entry_size)
+        .map(|entry| {
+            let request: TokenRequest = bytes_to_struct(entry);
 
-    if let Connection::Ws(mut ws) = client_profile.conn {
-        let _ = ws.close(None);
-    } else if let Connection::Tcp(stream) = client_profile.conn {
-        let _ = stream.shutdown(Shutdown::Both);
+            // Reject empty and unknown type flags
+            let dtype = u8::try_from(request.dtype)
+                .ok()
+                .and_then(TypeFlags::from_bits)
+                .filter(|dtype| !dtype.is_empty())
+                .ok_or(ErrorCode::InvalidType)?;
+
+            Ok(ClientSubscription {
+                token: request.token as usize,
+                dtype,
+            })
+        })
+        .collect()
+}
+
+pub fn handle_init(handle: Handle, request_id: u32, init: InitRequest) {
+    let client_profile = CLIENTS_LIST.get(handle).unwrap();
+    let mut session = client_profile.session.write().unwrap();
+
+    // Reject repeated init, unsupported versions and feed only modes
+    let error = if session.initialized {
+        Some(ErrorCode::AlreadyInitialized)
+    } else if init.version == 0 || init.version > PROTOCOL_VERSION {
+        Some(ErrorCode::UnsupportedVersion)
+    } else if matches!(init.mode, Mode::Kafka) {
+        Some(ErrorCode::UnsupportedMode)
+    } else {
+        None
+    };
+
+    if let Some(code) = error {
+        drop(session);
+        handle_invalid_request(handle, request_id, code);
+        return;
     }
 
-    println!("{} disconnected", idx);
+    session.format = init.format;
+    session.mode = init.mode;
+    session.slow_consumer = init.slow_consumer.unwrap_or(session.slow_consumer);
+    session.session_id = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
+    session.initialized = true;
+
+    let session_id = session.session_id;
+
+    drop(session);
+
+    let response = InitResponse {
+        session_id,
+        protocol_version: PROTOCOL_VERSION,
+        server_version: [
+            env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
+            env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
+            env!("CARGO_PKG_VERSION_PATCH").parse().unwrap(),
+        ],
+        max_subscriptions: MAX_CLIENT_SUBSCRIPTIONS as u32,
+        max_request_size: INPUT_BUF_SIZE as u32,
+    };
+
+    let json = JsonMessage::Init {
+        id: request_id,
+        response: &response,
+    };
+
+    if !send_response(handle, INIT_RESPONSE, request_id, &response, &json) || !init.market_messages {
+        return;
+    }
+
+    // Recent market messages follow init response
+    if join_market_messages(&client_profile, request_id).is_err() {
+        handle_disconnection(handle);
+    }
+}
+
+pub fn handle_token_subscribe(handle: Handle, request_id: u32, subscriptions: Vec<ClientSubscription>) {
+    let client_profile = CLIENTS_LIST.get(handle).unwrap();
+    let mode = client_profile.session().mode;
+    let mut client_subscriptions = client_profile
+        .subscriptions
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let mut results = Vec::with_capacity(subscriptions.len());
+    let mut accepted = Vec::new();
+
+    // Each token is accepted or rejected on its own
+    for subscription in subscriptions {
+        let Some(slot) = TOKENS.slot(subscription.token) else {
+            results.push(TokenResult::rejected(subscription.token, ErrorCode::InvalidToken));
+            continue;
+        };
+
+        let subscription_count = client_subscriptions.len();
+
+        match client_subscriptions
+            .iter_mut()
+            .find(|existing| existing.token == subscription.token)
+        {
+            Some(existing) => existing.dtype |= subscription.dtype,
+            None if subscription_count < MAX_CLIENT_SUBSCRIPTIONS => client_subscriptions.push(subscription),
+            None => {
+                results.push(TokenResult::rejected(subscription.token, ErrorCode::LimitExceeded));
+                continue;
+            }
+        }
+
+        let added = SUBSCRIPTIONS[slot]
+            .write()
+            .unwrap()
+            .subscribe(handle, subscription.dtype, mode);
+
+        if !added.is_empty() {
+            forward_upstream(UpstreamRequest::Subscribe(ClientSubscription {
+                token: subscription.token,
+                dtype: added,
+            }));
+        }
+
+        results.push(TokenResult::accepted(subscription.token));
+        accepted.push(subscription);
+    }
+
+    // Disconnection takes it while sending
+    drop(client_subscriptions);
+
+    // Ack first so snapshots arrive for known tokens
+    if !send_ack(handle, request_id, &results) {
+        return;
+    }
+
+    // Send latest known values without waiting for next tick
+    for subscription in accepted {
+        if !send_snapshot(handle, subscription) {
+            return;
+        }
+    }
+}
+
+pub fn handle_token_unsubscribe(handle: Handle, request_id: u32, subscriptions: Vec<ClientSubscription>) {
+    let client_profile = CLIENTS_LIST.get(handle).unwrap();
+    let mode = client_profile.session().mode;
+    let mut client_subscriptions = client_profile
+        .subscriptions
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let mut results = Vec::with_capacity(subscriptions.len());
+
+    for subscription in subscriptions {
+        let Some(slot) = TOKENS.slot(subscription.token) else {
+            results.push(TokenResult::rejected(subscription.token, ErrorCode::InvalidToken));
+            continue;
+        };
+
+        let Some(existing) = client_subscriptions
+            .iter_mut()
+            .find(|existing| existing.token == subscription.token)
+        else {
+            results.push(TokenResult::rejected(subscription.token, ErrorCode::NotSubscribed));
+            continue;
+        };
+
+        existing.dtype.remove(subscription.dtype);
+
+        let removed = SUBSCRIPTIONS[slot]
+            .write()
+            .unwrap()
+            .unsubscribe(handle, subscription.dtype, mode);
+
+        if !removed.is_empty() {
+            forward_upstream(UpstreamRequest::Unsubscribe(ClientSubscription {
+                token: subscription.token,
+                dtype: removed,
+            }));
+        }
+
+        results.push(TokenResult::accepted(subscription.token));
+    }
+
+    // Drop tokens with no types left
+    client_subscriptions.retain(|subscription| !subscription.dtype.is_empty());
+
+    drop(client_subscriptions);
+
+    send_ack(handle, request_id, &results);
+}
+
+pub fn handle_udp_switch(handle: Handle, request_id: u32, address: SocketAddr) {
+    let client_profile = CLIENTS_LIST.get(handle).unwrap();
+
+    // Only allow sending to host of the client itself
+    let peer_address = client_profile.conn().peer_addr();
+
+    if !peer_address.is_ok_and(|peer_address| peer_address.ip() == address.ip()) {
+        handle_invalid_request(handle, request_id, ErrorCode::InvalidAddress);
+        return;
+    }
+
+    let mut session = client_profile.session.write().unwrap();
+    let mode = session.mode;
+
+    session.udp_address = Some(address);
+    session.mode = Mode::Udp;
+
+    drop(session);
+
+    let subscriptions = client_profile
+        .subscriptions
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+
+    for subscription in &subscriptions {
+        subscribers(subscription.token)
+            .write()
+            .unwrap()
+            .switch_mode(handle, mode, Mode::Udp);
+    }
+
+    if !send_ack(handle, request_id, &[]) {
+        return;
+    }
+
+    // New destination has seen nothing yet, clients in udp mode since init had snapshots dropped
+    for subscription in subscriptions {
+        if !send_snapshot(handle, subscription) {
+            return;
+        }
+    }
+}
+
+// Tell client why its request was rejected
+pub fn handle_invalid_request(handle: Handle, request_id: u32, code: ErrorCode) {
+    let response = ErrorResponse::new(code);
+    let json = JsonMessage::Error {
+        id: request_id,
+        code: code as u16,
+        message: code.message(),
+    };
+
+    send_response(handle, ERROR_RESPONSE, request_id, &response, &json);
+}
+
+// Keep upstream distributor subscribed to what clients need
+fn forward_upstream(request: UpstreamRequest) {
+    if let Role::Redistributor = settings::get().role {
+        UPSTREAM_REQUESTS.push(request);
+    }
+}
+
+// Encode response in format of client and write it
+// Returns false if client got disconnected
+fn send_response<T: Copy>(handle: Handle, msg_type: u16, request_id: u32, native: &T, json: &JsonMessage) -> bool {
+    let format = CLIENTS_LIST.get(handle).unwrap().session().format;
+    let mut payload = [0; MESSAGE_BUF_SIZE];
+
+    let Some(size) = encode(format, native, json, &mut payload) else {
+        return true;
+    };
+
+    send_payload(handle, msg_type, request_id, &payload[..size])
+}
+
+// Acknowledge request with result of each token
+// Large batches are split into several acks, all but the last one are marked with more
+// Returns false if client got disconnected
+fn send_ack(handle: Handle, request_id: u32, results: &[TokenResult]) -> bool {
+    let format = CLIENTS_LIST.get(handle).unwrap().session().format;
+    let mut payload = [0; MESSAGE_BUF_SIZE];
+
+    // Requests without tokens are still acknowledged once
+    let count = results.len().div_ceil(ACK_ENTRIES_PER_MESSAGE).max(1);
+
+    for i in 0..count {
+        let start = i * ACK_ENTRIES_PER_MESSAGE;
+        let end = results.len().min(start + ACK_ENTRIES_PER_MESSAGE);
+
+        let Some(size) = encode_ack(format, request_id, &results[start..end], i + 1 < count, &mut payload) else {
+            continue;
+        };
+
+        if !send_payload(handle, ACK_RESPONSE, request_id, &payload[..size]) {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Write encoded payload to control connection of client
+// Returns false if client got disconnected
+fn send_payload(handle: Handle, msg_type: u16, request_id: u32, payload: &[u8]) -> bool {
+    let client_profile = CLIENTS_LIST.get(handle).unwrap();
+
+    if client_profile.send(msg_type, request_id, payload).is_err() {
+        handle_disconnection(handle);
+        return false;
+    }
+
+    true
+}
+
+// Send latest value of each subscribed type from data store
+// Returns false if client got disconnected
+fn send_snapshot(handle: Handle, subscription: ClientSubscription) -> bool {
+    let client_profile = CLIENTS_LIST.get(handle).unwrap();
+    let format = client_profile.session().format;
+    let slot = TOKENS.slot(subscription.token).unwrap();
+
+    let mut payload = [0; MESSAGE_BUF_SIZE];
+
+    for dtype in DataType::ALL {
+        if !subscription.dtype.contains(dtype.into()) {
+            continue;
+        }
+
+        let Some(data) = DATA_STORE[slot].get(dtype) else {
+            continue;
+        };
+
+        let update = UpdateMessage {
+            token: subscription.token as u32,
+            data: &data,
+        };
+
+        let Some(size) = encode_update(format, true, &update, &mut payload) else {
+            continue;
+        };
+
+        if send_data(&client_profile, SNAPSHOT_MESSAGE, 0, &payload[..size]).is_err() {
+            handle_disconnection(handle);
+            return false;
+        }
+    }
+
+    true
+}
+
+// Subscriber list of token held by a client
+// Clients only keep accepted tokens, so token is part of universe
+fn subscribers(token: usize) -> &'static RwLock<Subscription> {
+    &SUBSCRIPTIONS[TOKENS.slot(token).unwrap()]
+}
+
+pub fn handle_disconnection(handle: Handle) {
+    let Some(client_profile) = CLIENTS_LIST.remove(handle) else {
+        return;
+    };
+
+    let mode = client_profile.session().mode;
+    let subscriptions = client_profile
+        .subscriptions
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    // Remove client from subscriber lists
+    for subscription in subscriptions.iter() {
+        let removed = subscribers(subscription.token)
+            .write()
+            .unwrap()
+            .remove_client(handle, mode);
+
+        if !removed.is_empty() {
+            forward_upstream(UpstreamRequest::Unsubscribe(ClientSubscription {
+                token: subscription.token,
+                dtype: removed,
+            }));
+        }
+    }
+
+    let mut conn = client_profile.conn();
+
+    // Best effort close frame
+    if let Connection::Ws(ws) = &mut *conn {
+        if let WsConnection::Open(socket) = ws.as_mut() {
+            let _ = socket.close(None);
+        }
+    }
+
+    // Socket is dropped with last reference to client, threads still holding one see it shut down
+    let _ = conn.shutdown(Shutdown::Both);
+
+    println!("{} disconnected", handle.idx());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{offset_of, size_of};
+
+    use crate::{
+        constants::{INIT_REQUEST, INPUT_BUF_SIZE, SUBSCRIBE_REQUEST, UNSUBSCRIBE_REQUEST},
+        types::{
+            packet::InputPacket,
+            protocol::{write_message, ErrorCode, NativeInitRequest, Request, TokenRequest},
+        },
+        utils::byte_utils::struct_to_bytes,
+    };
+
+    use super::parse_requests;
+
+    fn frame(msg_type: u16, request_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut buffer = [0; INPUT_BUF_SIZE];
+        let size = write_message(msg_type, request_id, payload, &mut buffer).unwrap();
+
+        buffer[..size].to_vec()
+    }
+
+    fn token_requests(tokens: &[u32]) -> Vec<u8> {
+        let mut payload = vec![0; tokens.len() * size_of::<TokenRequest>()];
+
+        for (i, &token) in tokens.iter().enumerate() {
+            let request = TokenRequest { token, dtype: 1 };
+            struct_to_bytes(&request, &mut payload[i * size_of::<TokenRequest>()..]);
+        }
+
+        payload
+    }
+
+    fn init(flags: u16) -> Vec<u8> {
+        let request = NativeInitRequest {
+            version: 1,
+            format: 0,
+            mode: 0,
+            flags,
+        };
+        let mut payload = vec![0; size_of::<NativeInitRequest>()];
+        struct_to_bytes(&request, &mut payload);
+
+        payload
+    }
+
+    // Append bytes as if read from socket and parse
+    fn read(packet: &mut InputPacket, data: &[u8]) -> Result<Vec<(u32, Request)>, u32> {
+        packet.0[packet.1..packet.1 + data.len()].copy_from_slice(data);
+        packet.1 += data.len();
+
+        let mut requests = Vec::new();
+        parse_requests(packet, &mut requests)?;
+
+        Ok(requests)
+    }
+
+    #[test]
+    fn partial_reads_are_kept_until_complete() {
+        let message = frame(SUBSCRIBE_REQUEST, 7, &token_requests(&[10, 20]));
+        let mut packet = InputPacket::new();
+
+        // Split inside header, then inside payload
+        assert!(read(&mut packet, &message[..3]).unwrap().is_empty());
+        assert!(read(&mut packet, &message[3..12]).unwrap().is_empty());
+        assert_eq!(packet.1, 12);
+
+        let requests = read(&mut packet, &message[12..]).unwrap();
+
+        assert_eq!(packet.1, 0);
+        assert!(matches!(
+            requests.as_slice(),
+            [(7, Request::Subscribe(subscriptions))]
+                if subscriptions.iter().map(|s| s.token).eq([10, 20])
+        ));
+    }
+
+    #[test]
+    fn multiple_requests_are_parsed_from_one_read() {
+        let unsubscribe = frame(UNSUBSCRIBE_REQUEST, 3, &token_requests(&[30]));
+        let mut data = frame(INIT_REQUEST, 1, &init(0));
+        data.extend(frame(SUBSCRIBE_REQUEST, 2, &token_requests(&[30])));
+        data.extend(&unsubscribe[..5]);
+
+        let mut packet = InputPacket::new();
+        let requests = read(&mut packet, &data).unwrap();
+
+        assert!(matches!(
+            requests.as_slice(),
+            [(1, Request::Init(_)), (2, Request::Subscribe(_))]
+        ));
+
+        // Partial message is moved to start of buffer
+        assert_eq!(&packet.0[..packet.1], &unsubscribe[..5]);
+
+        let requests = read(&mut packet, &unsubscribe[5..]).unwrap();
+        assert!(matches!(requests.as_slice(), [(3, Request::Unsubscribe(_))]));
+    }
+
+    #[test]
+    fn oversize_message_is_rejected() {
+        let mut message = frame(SUBSCRIBE_REQUEST, 9, &[]);
+        // Length which can never fit in input buffer
+        message[2..4].copy_from_slice(&(INPUT_BUF_SIZE as u16).to_ne_bytes());
+
+        let mut packet = InputPacket::new();
+
+        assert_eq!(read(&mut packet, &message).err(), Some(9));
+    }
+
+    #[test]
+    fn init_flags_are_optional() {
+        let mut packet = InputPacket::new();
+        let with_flags = frame(INIT_REQUEST, 1, &init(1));
+        let without_flags = frame(INIT_REQUEST, 2, &init(1)[..offset_of!(NativeInitRequest, flags)]);
+        let truncated = frame(INIT_REQUEST, 3, &init(1)[..3]);
+
+        let mut data = with_flags;
+        data.extend(without_flags);
+        data.extend(truncated);
+
+        let requests = read(&mut packet, &data).unwrap();
+
+        assert!(matches!(
+            requests.as_slice(),
+            [
+                (1, Request::Init(with_flags)),
+                (2, Request::Init(without_flags)),
+                (3, Request::Invalid(ErrorCode::MalformedRequest)),
+            ] if with_flags.market_messages && !without_flags.market_messages
+        ));
+    }
 }
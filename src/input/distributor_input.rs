@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    mem::size_of,
+    net::TcpStream,
+    sync::PoisonError,
+    thread,
+};
+
+use crate::{
+    constants::{
+        ACK_RESPONSE, DISTRIBUTOR_READ_TIMEOUT, DISTRIBUTOR_RECONNECT_DELAY, ERROR_RESPONSE, INIT_REQUEST,
+        INPUT_BUF_SIZE, MARKET_MESSAGE, OUTPUT_BUF_SIZE, PROTOCOL_VERSION, SNAPSHOT_MESSAGE, SUBSCRIBE_REQUEST,
+        UNSUBSCRIBE_REQUEST, UPDATE_MESSAGE,
+    },
+    globals::{MARKET_HISTORY, UPSTREAM_REQUESTS},
+    types::{
+        client_profile::{ClientSubscription, TypeFlags},
+        packet::OutputPacket,
+        protocol::{
+            AckHeader, ErrorResponse, MessageHeader, NativeInitRequest, TokenAck, TokenRequest,
+            INIT_FLAG_MARKET_MESSAGES,
+        },
+        settings,
+        subscription::UpstreamRequest,
+    },
+    utils::{
+        byte_utils::{bytes_to_struct, struct_to_bytes},
+        error_utils::{interrupted, would_block},
+    },
+};
+
+use super::feed_input::route_packet;
+
+const HEADER_SIZE: usize = size_of::<MessageHeader>();
+
+// Upstream echoes it on market messages replayed from its history
+const INIT_REQUEST_ID: u32 = 1;
+
+// Consumes feed from an upstream distributor as a regular client
+pub struct DistributorInput {
+    address: String,
+    // Everything subscribed upstream, replayed on reconnect
+    subscriptions: HashMap<usize, TypeFlags>,
+    buffer: [u8; HEADER_SIZE + OUTPUT_BUF_SIZE],
+    buffered: usize,
+    // Replayed market messages are skipped while already relayed before reconnect
+    skip_replayed: bool,
+}
+
+impl DistributorInput {
+    pub fn new() -> Self {
+        let address = settings::get()
+            .distributor_address
+            .clone()
+            .filter(|address| !address.is_empty())
+            .expect("Distributor address not provided");
+
+        Self {
+            address,
+            subscriptions: HashMap::new(),
+            buffer: [0; HEADER_SIZE + OUTPUT_BUF_SIZE],
+            buffered: 0,
+            skip_replayed: false,
+        }
+    }
+
+    pub fn start_input(&mut self) {
+        loop {
+            let mut stream = match self.connect() {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Unable to connect distributor {} {:?}", self.address, e);
+                    thread::sleep(DISTRIBUTOR_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+
+            println!("Connected to distributor {}", self.address);
+
+            if let Err(e) = self.consume(&mut stream) {
+                eprintln!("Disconnected from distributor {:?}", e);
+            }
+
+            thread::sleep(DISTRIBUTOR_RECONNECT_DELAY);
+        }
+    }
+
+    fn connect(&mut self) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.address)?;
+
+        stream.set_nodelay(true)?;
+        // Wake up periodically to send pending subscriptions
+        stream.set_read_timeout(Some(DISTRIBUTOR_READ_TIMEOUT))?;
+
+        self.buffered = 0;
+        self.skip_replayed = true;
+
+        send_init(&mut stream)?;
+
+        // Replay existing subscriptions
+        let subscriptions = self
+            .subscriptions
+            .iter()
+            .map(|(token, dtype)| ClientSubscription {
+                token: *token,
+                dtype: *dtype,
+            })
+            .collect::<Vec<ClientSubscription>>();
+
+        send_token_requests(&mut stream, SUBSCRIBE_REQUEST, &subscriptions)?;
+
+        Ok(stream)
+    }
+
+    fn consume(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        loop {
+            // Forward subscription changes
+            let mut subscribe = Vec::new();
+            let mut unsubscribe = Vec::new();
+
+            while let Some(request) = UPSTREAM_REQUESTS.pop() {
+                match request {
+                    UpstreamRequest::Subscribe(subscription) => {
+                        let dtype = self
+                            .subscriptions
+                            .entry(subscription.token)
+                            .or_insert(TypeFlags::empty());
+
+                        *dtype |= subscription.dtype;
+                        subscribe.push(subscription);
+                    }
+                    UpstreamRequest::Unsubscribe(subscription) => {
+                        if let Some(dtype) = self.subscriptions.get_mut(&subscription.token) {
+                            dtype.remove(subscription.dtype);
+
+                            if dtype.is_empty() {
+                                self.subscriptions.remove(&subscription.token);
+                            }
+                        }
+
+                        unsubscribe.push(subscription);
+                    }
+                }
+            }
+
+            send_token_requests(stream, SUBSCRIBE_REQUEST, &subscribe)?;
+            send_token_requests(stream, UNSUBSCRIBE_REQUEST, &unsubscribe)?;
+
+            match stream.read(&mut self.buffer[self.buffered..]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(size) => {
+                    self.buffered += size;
+                    self.process_messages()?;
+                }
+                Err(e) if would_block(&e) || interrupted(&e) || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Route every complete message in buffer
+    fn process_messages(&mut self) -> io::Result<()> {
+        let mut offset = 0;
+
+        while self.buffered - offset >= HEADER_SIZE {
+            let header: MessageHeader = bytes_to_struct(&self.buffer[offset..]);
+            let length = header.length as usize;
+
+            if length > OUTPUT_BUF_SIZE {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+
+            // Wait for rest of message
+            if self.buffered - offset < HEADER_SIZE + length {
+                break;
+            }
+
+            let payload = &self.buffer[offset + HEADER_SIZE..offset + HEADER_SIZE + length];
+
+            let replayed = header.msg_type == MARKET_MESSAGE && header.request_id == INIT_REQUEST_ID;
+
+            // Market messages are relayed to clients of this distributor too
+            if replayed && is_relayed(&mut self.skip_replayed, payload) {
+                // Already relayed before reconnect
+            } else if matches!(header.msg_type, UPDATE_MESSAGE | SNAPSHOT_MESSAGE | MARKET_MESSAGE) {
+                let mut packet = OutputPacket::new();
+                packet.0[..length].copy_from_slice(payload);
+                packet.1 = length;
+
+                route_packet(packet);
+            } else if header.msg_type == ERROR_RESPONSE && length >= size_of::<ErrorResponse>() {
+                let error: ErrorResponse = bytes_to_struct(payload);
+
+                eprintln!("Distributor rejected request, code {}", error.code);
+            } else if header.msg_type == ACK_RESPONSE && length >= size_of::<AckHeader>() {
+                log_rejected_tokens(payload);
+            }
+
+            offset += HEADER_SIZE + length;
+        }
+
+        // Move partial message to start
+        self.buffer.copy_within(offset..self.buffered, 0);
+        self.buffered -= offset;
+
+        Ok(())
+    }
+}
+
+// Upstream replays its recent market messages on every init, oldest first
+// Those relayed before reconnect are skipped, the rest were missed meanwhile
+fn is_relayed(skip: &mut bool, payload: &[u8]) -> bool {
+    if !*skip {
+        return false;
+    }
+
+    let history = MARKET_HISTORY.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if history.iter().any(|packet| &packet.0[..packet.1] == payload) {
+        return true;
+    }
+
+    *skip = false;
+
+    false
+}
+
+// Upstream can reject tokens, for example when its subscription limit is reached
+fn log_rejected_tokens(payload: &[u8]) {
+    let header: AckHeader = bytes_to_struct(payload);
+    let entries = &payload[size_of::<AckHeader>()..];
+
+    for entry in entries.chunks_exact(size_of::<TokenAck>()).take(header.count as usize) {
+        let ack: TokenAck = bytes_to_struct(entry);
+
+        if ack.code != 0 {
+            eprintln!("Distributor rejected token {}, code {}", ack.token, ack.code);
+        }
+    }
+}
+
+fn send_init(stream: &mut TcpStream) -> io::Result<()> {
+    let init = NativeInitRequest {
+        version: PROTOCOL_VERSION,
+        // Native format over tcp
+        format: 0,
+        mode: 0,
+        flags: INIT_FLAG_MARKET_MESSAGES,
+    };
+    let mut payload = [0; size_of::<NativeInitRequest>()];
+
+    struct_to_bytes(&init, &mut payload);
+
+    send_message(stream, INIT_REQUEST, INIT_REQUEST_ID, &payload)
+}
+
+fn send_token_requests(stream: &mut TcpStream, msg_type: u16, subscriptions: &[ClientSubscription]) -> io::Result<()> {
+    let entry_size = size_of::<TokenRequest>();
+    let mut payload = [0; INPUT_BUF_SIZE - HEADER_SIZE];
+
+    // Split into messages which fit in request buffer of distributor
+    for chunk in subscriptions.chunks(payload.len() / entry_size) {
+        for (i, subscription) in chunk.iter().enumerate() {
+            let request = TokenRequest {
+                token: subscription.token as u32,
+                dtype: subscription.dtype.bits() as u32,
+            };
+
+            struct_to_bytes(&request, &mut payload[i * entry_size..]);
+        }
+
+        send_message(stream, msg_type, 0, &payload[..chunk.len() * entry_size])?;
+    }
+
+    Ok(())
+}
+
+fn send_message(stream: &mut TcpStream, msg_type: u16, request_id: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = [0; INPUT_BUF_SIZE];
+    let header = MessageHeader {
+        msg_type,
+        length: payload.len() as u16,
+        request_id,
+    };
+
+    struct_to_bytes(&header, &mut message);
+    message[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+
+    stream.write_all(&message[..HEADER_SIZE + payload.len()])
+}
@@ -1 +1,131 @@
+use std::{
+    hint,
+    mem::size_of,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    thread,
+};
 
+use crate::{
+    constants::{FEED_ERROR_DELAY, FEED_ERROR_MAX_DELAY},
+    globals::{DATA_STORE, MARKET_MESSAGES_QUEUE, SUBSCRIPTIONS, TOKENS, TOKEN_PACKETS_QUEUE},
+    metrics::{Metrics, METRICS},
+    output::{market::schedule_market_messages, schedule_token},
+    types::{
+        market_data::MarketData,
+        packet::{FeedHeader, OutputPacket},
+        settings,
+    },
+    utils::{
+        byte_utils::bytes_to_struct,
+        error_utils::{interrupted, would_block},
+    },
+};
+
+pub struct FeedInput {
+    socket: UdpSocket,
+}
+
+impl FeedInput {
+    pub fn new() -> Self {
+        let multicast_address: SocketAddrV4 = settings::get().udp_multicast_address.parse().unwrap();
+        let interface_ip: Ipv4Addr = settings::get().interface_ip.parse().unwrap();
+
+        // Bind on all interfaces with multicast port
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, multicast_address.port())).unwrap();
+
+        // Join group on configured interface
+        socket.join_multicast_v4(multicast_address.ip(), &interface_ip).unwrap();
+
+        socket.set_nonblocking(true).unwrap();
+
+        Self { socket }
+    }
+
+    pub fn start_input(&mut self) {
+        let mut packet = OutputPacket::new();
+        let mut error_delay = FEED_ERROR_DELAY;
+
+        loop {
+            match self.socket.recv(&mut packet.0) {
+                Ok(size) => {
+                    error_delay = FEED_ERROR_DELAY;
+                    packet.1 = size;
+                    route_packet(packet);
+                }
+                // No datagram available yet
+                Err(e) if would_block(&e) || interrupted(&e) => hint::spin_loop(),
+                // Back off so a persistent error doesn't spin and flood stderr
+                Err(e) => {
+                    eprintln!("Error receiving feed {:?}, retrying in {:?}", e, error_delay);
+                    thread::sleep(error_delay);
+                    error_delay = (error_delay * 2).min(FEED_ERROR_MAX_DELAY);
+                }
+            }
+        }
+    }
+}
+
+// Sort packet into token wise queues or market messages
+pub fn route_packet(packet: OutputPacket) {
+    // Drop packets too small to hold a header
+    if packet.1 < size_of::<FeedHeader>() {
+        return;
+    }
+
+    let header: FeedHeader = bytes_to_struct(&packet.0);
+
+    let Some(dtype) = header.data_type() else {
+        MARKET_MESSAGES_QUEUE.push(packet);
+        schedule_market_messages();
+        return;
+    };
+
+    // Tokens outside universe are dropped
+    let Some(slot) = TOKENS.slot(header.token as usize) else {
+        Metrics::increment(&METRICS.unknown_tokens);
+        return;
+    };
+
+    // Packets of unexpected size are dropped
+    let Some(data) = MarketData::parse(dtype, &packet.0[size_of::<FeedHeader>()..packet.1]) else {
+        Metrics::increment(&METRICS.invalid_packets);
+        return;
+    };
+
+    // Lighter types sent by source are deduplicated like derived ones
+    // Otherwise sources sending both depth and touch line publish each touch line twice
+    if !matches!(data, MarketData::Depth(_)) && !DATA_STORE[slot].changed(&data) {
+        return;
+    }
+
+    DATA_STORE[slot].write(data);
+
+    let subscription = SUBSCRIPTIONS[slot].read().unwrap();
+
+    // Skip dispatch when no one wants this type
+    let mut queued = subscription.is_wanted(dtype.into());
+
+    if queued {
+        TOKEN_PACKETS_QUEUE[slot].push(data);
+    }
+
+    // Lighter views are published only when their own fields change
+    for derived in data.derived().into_iter().flatten() {
+        if !DATA_STORE[slot].changed(&derived) {
+            continue;
+        }
+
+        DATA_STORE[slot].write(derived);
+
+        if subscription.is_wanted(derived.dtype().into()) {
+            TOKEN_PACKETS_QUEUE[slot].push(derived);
+            queued = true;
+        }
+    }
+
+    drop(subscription);
+
+    if queued {
+        schedule_token(slot);
+    }
+}
@@ -0,0 +1,200 @@
+use rdkafka::{
+    consumer::{BaseConsumer, Consumer},
+    error::KafkaResult,
+    ClientConfig, Message, Offset, TopicPartitionList,
+};
+
+use crate::{
+    constants::{KAFKA_GROUP_ID, KAFKA_METADATA_TIMEOUT, KAFKA_POLL_TIMEOUT, OUTPUT_BUF_SIZE},
+    types::{
+        packet::OutputPacket,
+        settings::{self, KafkaOffset},
+    },
+};
+
+use super::feed_input::route_packet;
+
+pub struct KafkaInput {
+    consumer: BaseConsumer,
+}
+
+impl KafkaInput {
+    pub fn new() -> Self {
+        let settings = settings::get();
+
+        let address = settings.kafka_address.as_ref().expect("Kafka address not provided");
+        let topic = settings.kafka_topic.as_ref().expect("Kafka topic not provided");
+        let partitions = settings
+            .kafka_partition
+            .iter()
+            .map(|partition| *partition as i32)
+            .collect::<Vec<i32>>();
+
+        Self::connect(address, topic, &partitions, settings.kafka_offset).unwrap()
+    }
+
+    pub fn connect(address: &str, topic: &str, partitions: &[i32], offset: KafkaOffset) -> KafkaResult<Self> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", address)
+            .set("group.id", KAFKA_GROUP_ID)
+            .set("enable.auto.commit", "false")
+            .create()?;
+
+        let mut assignment = TopicPartitionList::new();
+
+        for partition in partitions {
+            let start = match offset {
+                KafkaOffset::Earliest => Offset::Beginning,
+                KafkaOffset::Latest => Offset::End,
+                KafkaOffset::Timestamp(timestamp) => Offset::Offset(timestamp),
+            };
+
+            assignment.add_partition_offset(topic, *partition, start)?;
+        }
+
+        // Resolve timestamps to offsets of each partition
+        if let KafkaOffset::Timestamp(_) = offset {
+            assignment = consumer.offsets_for_times(assignment, KAFKA_METADATA_TIMEOUT)?;
+        }
+
+        consumer.assign(&assignment)?;
+
+        Ok(Self { consumer })
+    }
+
+    pub fn start_input(&mut self) {
+        loop {
+            self.poll();
+        }
+    }
+
+    // Read one message if available and route it
+    // Returns true if a message was received
+    pub fn poll(&self) -> bool {
+        match self.consumer.poll(KAFKA_POLL_TIMEOUT) {
+            Some(Ok(message)) => {
+                let payload = message.payload().unwrap_or_default();
+
+                // Drop messages which can not fit in a packet
+                if payload.len() > OUTPUT_BUF_SIZE {
+                    eprintln!("Dropping kafka message of size {}", payload.len());
+                    return true;
+                }
+
+                let mut packet = OutputPacket::new();
+                packet.0[..payload.len()].copy_from_slice(payload);
+                packet.1 = payload.len();
+
+                route_packet(packet);
+
+                true
+            }
+            Some(Err(e)) => {
+                eprintln!("Error receiving kafka message {:?}", e);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        mem::size_of,
+        time::{Duration, Instant},
+    };
+
+    use rdkafka::{
+        mocking::MockCluster,
+        producer::{BaseProducer, BaseRecord, Producer},
+        ClientConfig,
+    };
+
+    use crate::{
+        constants::FEED_DEPTH,
+        globals::{SETTINGS, SUBSCRIPTIONS, TOKENS, TOKEN_PACKETS_QUEUE},
+        types::{
+            client_profile::TypeFlags,
+            market_data::{Depth, MarketData},
+            packet::FeedHeader,
+            reuse_array::Handle,
+            settings::{KafkaOffset, Mode, Settings},
+        },
+        utils::byte_utils::struct_to_bytes,
+    };
+
+    use super::KafkaInput;
+
+    const TOPIC: &str = "feed";
+
+    // Token universe is sized from settings, independent of the shipped config
+    fn init_settings(token: u32) {
+        let settings: Settings = serde_json::from_value(serde_json::json!({
+            "kafka_partition": [0],
+            "tcp_address": "127.0.0.1:0",
+            "ws_address": "127.0.0.1:0",
+            "mode": "kafka",
+            "interface_ip": "127.0.0.1",
+            "udp_multicast_address": "",
+            "tokens": { "ranges": [[token, token + 1]] },
+        }))
+        .unwrap();
+
+        let _ = SETTINGS.set(settings);
+    }
+
+    #[test]
+    fn routes_messages_from_earliest_offset() {
+        let token = 31001;
+        init_settings(token);
+        let slot = TOKENS.slot(token as usize).unwrap();
+
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic(TOPIC, 1, 1).unwrap();
+
+        let producer: BaseProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()
+            .unwrap();
+
+        let depth = Depth {
+            ltp: 2500,
+            volume: 10,
+            ..Default::default()
+        };
+        let header = FeedHeader {
+            msg_type: FEED_DEPTH,
+            length: (size_of::<FeedHeader>() + size_of::<Depth>()) as u16,
+            token,
+        };
+        let mut payload = [0; size_of::<FeedHeader>() + size_of::<Depth>()];
+        struct_to_bytes(&header, &mut payload);
+        struct_to_bytes(&depth, &mut payload[size_of::<FeedHeader>()..]);
+
+        producer
+            .send(BaseRecord::<(), _>::to(TOPIC).partition(0).payload(&payload[..]))
+            .unwrap();
+        producer.flush(Duration::from_secs(5)).unwrap();
+
+        // Packets are only queued for tokens with subscribers
+        SUBSCRIPTIONS[slot]
+            .write()
+            .unwrap()
+            .subscribe(Handle::from(0), TypeFlags::ALL, Mode::Tcp);
+
+        // Message is produced before consumer starts
+        let input = KafkaInput::connect(&cluster.bootstrap_servers(), TOPIC, &[0], KafkaOffset::Earliest).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TOKEN_PACKETS_QUEUE[slot].is_empty() && Instant::now() < deadline {
+            input.poll();
+        }
+
+        let Some(MarketData::Depth(received)) = TOKEN_PACKETS_QUEUE[slot].pop() else {
+            panic!("expected depth");
+        };
+
+        assert_eq!(received, depth);
+    }
+}
@@ -1,2 +1,4 @@
 pub mod client_input;
+pub mod distributor_input;
 pub mod feed_input;
+pub mod kafka_input;
@@ -1,9 +1,17 @@
-use input::client_input::ClientInput;
+use constants::{CLIENT_THREADS, OUTPUT_THREADS};
+use globals::MODE;
+use input::{
+    client_input::ClientInput, distributor_input::DistributorInput, feed_input::FeedInput, kafka_input::KafkaInput,
+};
+use output::Output;
+use threadpool::client_threadpool::ClientThreadpool;
+use types::settings::{self, Mode, Role};
+use utils::thread_utils::{self, ThreadRole};
 
 mod constants;
 mod globals;
 mod input;
-mod macros;
+mod metrics;
 mod output;
 mod threadpool;
 mod types;
@@ -11,7 +19,33 @@ mod utils;
 
 fn main() {
     globals::init();
-    let mut client_input = ClientInput::new();
 
-    client_input.start_input();
+    metrics::start_reporting();
+
+    // Start writing queued output of clients
+    let client_tpool = ClientThreadpool::new(CLIENT_THREADS);
+    client_tpool.start_tpool();
+
+    // Start dispatching feed to clients
+    let output = Output::new(OUTPUT_THREADS);
+    output.start_output();
+
+    // Start feed receiver
+    match (settings::get().role, *MODE) {
+        (Role::Redistributor, _) => {
+            thread_utils::spawn(ThreadRole::FeedInput, || DistributorInput::new().start_input());
+        }
+        (_, Mode::Udp) => {
+            thread_utils::spawn(ThreadRole::FeedInput, || FeedInput::new().start_input());
+        }
+        (_, Mode::Kafka) => {
+            thread_utils::spawn(ThreadRole::FeedInput, || KafkaInput::new().start_input());
+        }
+        (_, Mode::Tcp) => {}
+    }
+
+    // Poll clients on a named thread, so it can be pinned like the rest
+    let client_input = thread_utils::spawn(ThreadRole::ClientInput, || ClientInput::new().start_input());
+
+    let _ = client_input.join();
 }
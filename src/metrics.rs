@@ -0,0 +1,83 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    thread::{self, JoinHandle},
+};
+
+use crate::{constants::METRICS_INTERVAL, globals::CLIENTS_LIST, types::settings};
+
+// Counters of events worth watching while running
+pub struct Metrics {
+    // Updates held back by conflate policy
+    pub conflated_updates: AtomicU64,
+    // Updates discarded by drop policy
+    pub dropped_updates: AtomicU64,
+    // Clients disconnected by disconnect policy
+    pub slow_consumer_disconnects: AtomicU64,
+    // Times a dispatcher went to sleep waiting for work
+    pub dispatcher_parks: AtomicU64,
+    // Feed packets dropped for size not matching their type
+    pub invalid_packets: AtomicU64,
+    // Feed packets dropped for token outside universe
+    pub unknown_tokens: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            conflated_updates: AtomicU64::new(0),
+            dropped_updates: AtomicU64::new(0),
+            slow_consumer_disconnects: AtomicU64::new(0),
+            dispatcher_parks: AtomicU64::new(0),
+            invalid_packets: AtomicU64::new(0),
+            unknown_tokens: AtomicU64::new(0),
+        }
+    }
+
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> [u64; 8] {
+        [
+            self.conflated_updates.load(Ordering::Relaxed),
+            self.dropped_updates.load(Ordering::Relaxed),
+            self.slow_consumer_disconnects.load(Ordering::Relaxed),
+            self.dispatcher_parks.load(Ordering::Relaxed),
+            self.invalid_packets.load(Ordering::Relaxed),
+            self.unknown_tokens.load(Ordering::Relaxed),
+            // Connected clients and slots ready for reuse
+            CLIENTS_LIST.len() as u64,
+            CLIENTS_LIST.free_count() as u64,
+        ]
+    }
+}
+
+// Print counters periodically, only when they changed
+pub fn start_reporting() -> JoinHandle<()> {
+    let wait_strategy = settings::get().wait_strategy.name();
+
+    println!("Metrics wait_strategy={}", wait_strategy);
+
+    thread::spawn(move || {
+        let mut last = METRICS.snapshot();
+
+        loop {
+            thread::sleep(METRICS_INTERVAL);
+
+            let current = METRICS.snapshot();
+
+            if current == last {
+                continue;
+            }
+
+            println!(
+                "Metrics wait_strategy={} conflated_updates={} dropped_updates={} slow_consumer_disconnects={} dispatcher_parks={} invalid_packets={} unknown_tokens={} clients={} free_client_slots={}",
+                wait_strategy, current[0], current[1], current[2], current[3], current[4], current[5], current[6], current[7]
+            );
+
+            last = current;
+        }
+    })
+}
@@ -0,0 +1,204 @@
+use std::mem::size_of;
+
+use serde::Serialize;
+
+use crate::{
+    types::{
+        client_profile::Format,
+        market_data::{MarketData, Positional},
+        packet::{FeedHeader, OutputPacket},
+        protocol::{AckHeader, InitResponse, TokenAck, TokenResult},
+    },
+    utils::byte_utils::{bytes_to_struct, struct_to_bytes},
+};
+
+// Messages as seen by json clients
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JsonMessage<'a> {
+    Init {
+        id: u32,
+        #[serde(flatten)]
+        response: &'a InitResponse,
+    },
+    Update(&'a UpdateMessage<'a>),
+    Snapshot(&'a UpdateMessage<'a>),
+    Error {
+        id: u32,
+        code: u16,
+        message: &'a str,
+    },
+    Ack {
+        id: u32,
+        results: Vec<JsonTokenResult>,
+        more: bool,
+    },
+    Market(&'a MarketMessage<'a>),
+}
+
+#[derive(Serialize)]
+pub struct JsonTokenResult {
+    pub token: usize,
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct UpdateMessage<'a> {
+    pub token: u32,
+    // Serialized as dtype and data fields
+    #[serde(flatten)]
+    pub data: &'a MarketData,
+}
+
+// Exchange wide message, not tied to a token
+#[derive(Serialize)]
+pub struct MarketMessage<'a> {
+    // Type of feed packet
+    pub msg_type: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> MarketMessage<'a> {
+    // Returns None for token wise packets
+    pub fn from_packet(packet: &'a OutputPacket) -> Option<Self> {
+        let header: FeedHeader = bytes_to_struct(&packet.0);
+
+        if header.data_type().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            msg_type: header.msg_type,
+            data: &packet.0[size_of::<FeedHeader>()..packet.1],
+        })
+    }
+}
+
+// Encode message in format of client
+// Returns encoded size or None if it does not fit in buffer
+pub fn encode<T: Copy>(format: Format, native: &T, json: &JsonMessage, buffer: &mut [u8]) -> Option<usize> {
+    match format {
+        Format::Native => {
+            if buffer.len() < size_of::<T>() {
+                return None;
+            }
+
+            struct_to_bytes(native, buffer);
+
+            Some(size_of::<T>())
+        }
+        Format::Json | Format::JsonArray => write_json(json, buffer),
+    }
+}
+
+// Encode update in format of client
+// Native clients receive it as feed packet, snapshots are told apart by message type
+pub fn encode_update(format: Format, snapshot: bool, update: &UpdateMessage, buffer: &mut [u8]) -> Option<usize> {
+    match format {
+        Format::Native => update.data.write_packet(update.token, buffer),
+        Format::Json if snapshot => write_json(&JsonMessage::Snapshot(update), buffer),
+        Format::Json => write_json(&JsonMessage::Update(update), buffer),
+        // Positional fields instead of object
+        Format::JsonArray => {
+            let tag = if snapshot { "snapshot" } else { "update" };
+
+            write_json(
+                &(tag, update.token, update.data.dtype(), Positional(update.data)),
+                buffer,
+            )
+        }
+    }
+}
+
+// Encode market message in format of client
+// Native clients receive the packet as is
+pub fn encode_market(format: Format, packet: &[u8], message: &MarketMessage, buffer: &mut [u8]) -> Option<usize> {
+    match format {
+        Format::Native => {
+            let size = packet.len();
+
+            if buffer.len() < size {
+                return None;
+            }
+
+            buffer[..size].copy_from_slice(packet);
+
+            Some(size)
+        }
+        Format::Json => write_json(&JsonMessage::Market(message), buffer),
+        Format::JsonArray => write_json(&("market", message.msg_type, message.data), buffer),
+    }
+}
+
+// Encode acknowledgement of token requests in format of client
+// Native clients receive ack header followed by token acks
+pub fn encode_ack(
+    format: Format,
+    request_id: u32,
+    results: &[TokenResult],
+    more: bool,
+    buffer: &mut [u8],
+) -> Option<usize> {
+    match format {
+        Format::Native => {
+            let header_size = size_of::<AckHeader>();
+            let entry_size = size_of::<TokenAck>();
+            let size = header_size + results.len() * entry_size;
+
+            if buffer.len() < size {
+                return None;
+            }
+
+            let header = AckHeader {
+                count: results.len() as u16,
+                more: more as u16,
+            };
+
+            struct_to_bytes(&header, buffer);
+
+            for (i, result) in results.iter().enumerate() {
+                let ack = TokenAck {
+                    token: result.token as u32,
+                    code: result.error.map_or(0, |code| code as u32),
+                };
+
+                struct_to_bytes(&ack, &mut buffer[header_size + i * entry_size..]);
+            }
+
+            Some(size)
+        }
+        Format::Json | Format::JsonArray => {
+            let results = results
+                .iter()
+                .map(|result| JsonTokenResult {
+                    token: result.token,
+                    accepted: result.error.is_none(),
+                    code: result.error.map(|code| code as u16),
+                    reason: result.error.map(|code| code.message()),
+                })
+                .collect();
+
+            write_json(
+                &JsonMessage::Ack {
+                    id: request_id,
+                    results,
+                    more,
+                },
+                buffer,
+            )
+        }
+    }
+}
+
+fn write_json<T: Serialize>(value: &T, buffer: &mut [u8]) -> Option<usize> {
+    let capacity = buffer.len();
+    let mut writer = &mut buffer[..];
+
+    serde_json::to_writer(&mut writer, value).ok()?;
+
+    Some(capacity - writer.len())
+}
@@ -0,0 +1,101 @@
+use std::{
+    io,
+    sync::{atomic::Ordering, PoisonError},
+};
+
+use crate::{
+    constants::{MARKET_HISTORY_SIZE, MARKET_MESSAGE, MESSAGE_BUF_SIZE},
+    globals::{CLIENTS_LIST, FEED_WORK_QUEUE, MARKET_HISTORY, MARKET_LOCK, MARKET_MESSAGES_QUEUE},
+    threadpool::drain_scheduled,
+    types::{
+        client_profile::ClientProfile,
+        packet::OutputPacket,
+        work::{FeedWork, WorkType},
+    },
+};
+
+use super::{
+    encoder::{encode_market, MarketMessage},
+    send_data, FORMAT_COUNT,
+};
+
+// Queue market messages for broadcast unless already scheduled
+pub fn schedule_market_messages() {
+    if !MARKET_LOCK.swap(true, Ordering::SeqCst) {
+        FEED_WORK_QUEUE.push(FeedWork {
+            work_type: WorkType::MarketMessage,
+            processing_fn: process_market_messages,
+        });
+    }
+}
+
+// Broadcast queued market messages in order, one thread at a time
+fn process_market_messages(_: &FeedWork) {
+    drain_scheduled(&MARKET_MESSAGES_QUEUE, &MARKET_LOCK, |packet| broadcast(&packet));
+}
+
+// Send message to every client which opted in and keep it for late joiners
+// History lock is held throughout, so a client joining meanwhile gets it exactly once
+fn broadcast(packet: &OutputPacket) {
+    let Some(message) = MarketMessage::from_packet(packet) else {
+        return;
+    };
+
+    let mut history = MARKET_HISTORY.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if history.len() == MARKET_HISTORY_SIZE {
+        history.pop_front();
+    }
+    history.push_back(*packet);
+
+    // Encoded lazily for formats which have clients
+    let mut buffers = [[0; MESSAGE_BUF_SIZE]; FORMAT_COUNT];
+    let mut sizes: [Option<Option<usize>>; FORMAT_COUNT] = [None; FORMAT_COUNT];
+
+    for handle in CLIENTS_LIST.snapshot() {
+        let Some(client_profile) = CLIENTS_LIST.get(handle) else {
+            continue;
+        };
+
+        let session = client_profile.session();
+
+        if !session.market_messages {
+            continue;
+        }
+
+        let format = session.format;
+        let buffer = &mut buffers[format as usize];
+
+        let size = *sizes[format as usize]
+            .get_or_insert_with(|| encode_market(format, &packet.0[..packet.1], &message, buffer));
+
+        if let Some(size) = size {
+            let _ = send_data(&client_profile, MARKET_MESSAGE, 0, &buffers[format as usize][..size]);
+        }
+    }
+}
+
+// Start sending market messages to client, beginning with recent ones
+// Recent ones echo init request id so clients can tell them from live ones
+pub fn join_market_messages(client_profile: &ClientProfile, request_id: u32) -> io::Result<()> {
+    let history = MARKET_HISTORY.lock().unwrap_or_else(PoisonError::into_inner);
+
+    client_profile.session.write().unwrap().market_messages = true;
+    let format = client_profile.session().format;
+
+    let mut payload = [0; MESSAGE_BUF_SIZE];
+
+    for packet in history.iter() {
+        let Some(message) = MarketMessage::from_packet(packet) else {
+            continue;
+        };
+
+        let Some(size) = encode_market(format, &packet.0[..packet.1], &message, &mut payload) else {
+            continue;
+        };
+
+        send_data(client_profile, MARKET_MESSAGE, request_id, &payload[..size])?;
+    }
+
+    Ok(())
+}
@@ -1 +1,223 @@
+pub mod encoder;
+pub mod market;
 
+use std::{io, mem::size_of, sync::atomic::Ordering, thread::JoinHandle};
+
+use crate::{
+    constants::{ERROR_RESPONSE, MESSAGE_BUF_SIZE, UPDATE_MESSAGE},
+    globals::{CLIENTS_LIST, FEED_WORK_QUEUE, SUBSCRIPTIONS, TOKENS, TOKEN_LOCKS, TOKEN_PACKETS_QUEUE, UDP_SOCKET},
+    metrics::{Metrics, METRICS},
+    threadpool::{client_threadpool::ClientThreadpool, drain_scheduled, ThreadPoolMaster},
+    types::{
+        client_profile::{ClientProfile, TypeFlags},
+        market_data::MarketData,
+        protocol::{write_message, DataType, ErrorCode, ErrorResponse, MessageHeader},
+        settings::{self, Mode, SlowConsumerPolicy},
+        work::{FeedWork, WorkType},
+    },
+    utils::{error_utils::would_block, thread_utils::ThreadRole},
+};
+
+use encoder::{encode, encode_update, JsonMessage, UpdateMessage};
+
+// Number of client formats, used to encode each packet once per format
+const FORMAT_COUNT: usize = 3;
+
+pub struct Output {
+    tpool: ThreadPoolMaster<FeedWork>,
+}
+
+impl Output {
+    pub fn new(num_threads: usize) -> Self {
+        Self {
+            tpool: ThreadPoolMaster::new(num_threads, FEED_WORK_QUEUE.clone(), ThreadRole::FeedOutput),
+        }
+    }
+
+    pub fn start_output(&self) -> JoinHandle<()> {
+        self.tpool.start_tpool()
+    }
+}
+
+// Queue token of slot for dispatch unless it is already scheduled
+pub fn schedule_token(slot: usize) {
+    if !TOKEN_LOCKS[slot].swap(true, Ordering::SeqCst) {
+        FEED_WORK_QUEUE.push(FeedWork {
+            work_type: WorkType::TokenWise(slot),
+            processing_fn: process_token,
+        });
+    }
+}
+
+// Dispatch all queued packets of token
+// Only one thread processes a token at a time to keep packets in order
+fn process_token(work: &FeedWork) {
+    let WorkType::TokenWise(slot) = work.work_type else {
+        return;
+    };
+
+    drain_scheduled(&TOKEN_PACKETS_QUEUE[slot], &TOKEN_LOCKS[slot], |data| {
+        dispatch_update(slot, &data)
+    });
+}
+
+// Send update to every client subscribed to its token and type
+fn dispatch_update(slot: usize, data: &MarketData) {
+    let token = TOKENS.token(slot);
+    let update = UpdateMessage {
+        token: token as u32,
+        data,
+    };
+    let dtype = data.dtype();
+    let flags = TypeFlags::from(dtype);
+
+    // Encoded lazily for formats which have subscribers
+    let mut buffers = [[0; MESSAGE_BUF_SIZE]; FORMAT_COUNT];
+    let mut sizes: [Option<Option<usize>>; FORMAT_COUNT] = [None; FORMAT_COUNT];
+
+    let subscription = SUBSCRIPTIONS[slot].read().unwrap();
+
+    // Udp clients are the ones missing from tcp list
+    let udp_clients = match subscription.total_udp_count() {
+        0 => &[],
+        _ => &subscription.all_clients[..],
+    };
+
+    let tcp_subscribers = subscription.tcp_clients.iter().map(|subscriber| (subscriber, false));
+    let udp_subscribers = udp_clients.iter().map(|subscriber| (subscriber, true));
+
+    for (subscriber, udp) in tcp_subscribers.chain(udp_subscribers) {
+        if !subscriber.dtype.intersects(flags) {
+            continue;
+        }
+
+        let Some(client_profile) = CLIENTS_LIST.get(subscriber.client) else {
+            continue;
+        };
+
+        let session = client_profile.session();
+
+        if udp != matches!(session.mode, Mode::Udp) {
+            continue;
+        }
+
+        let format = session.format;
+        let buffer = &mut buffers[format as usize];
+
+        let size = *sizes[format as usize].get_or_insert_with(|| encode_update(format, false, &update, buffer));
+
+        if let Some(size) = size {
+            let _ = send_update(&client_profile, token, dtype, &buffers[format as usize][..size]);
+        }
+    }
+}
+
+// Send token update, applying slow consumer policy of client once its output piles up
+fn send_update(client_profile: &ClientProfile, token: usize, dtype: DataType, payload: &[u8]) -> io::Result<()> {
+    let session = client_profile.session();
+
+    // Datagrams are never queued
+    if matches!(session.mode, Mode::Udp) {
+        return send_data(client_profile, UPDATE_MESSAGE, 0, payload);
+    }
+
+    let threshold = settings::get().slow_consumer_threshold;
+    let queued = client_profile.output.queued_bytes();
+
+    match session.slow_consumer {
+        SlowConsumerPolicy::Conflate => {
+            let Some(message) = client_profile.frame(UPDATE_MESSAGE, 0, payload) else {
+                return Err(io::ErrorKind::InvalidInput.into());
+            };
+
+            if client_profile
+                .output
+                .push_update(token as u32, dtype, message, queued > threshold)
+            {
+                Metrics::increment(&METRICS.conflated_updates);
+            }
+
+            ClientThreadpool::flush(client_profile.handle);
+
+            Ok(())
+        }
+        // Less important types are dropped earlier
+        SlowConsumerPolicy::Drop if queued > threshold * (drop_priority(dtype) + 1) => {
+            Metrics::increment(&METRICS.dropped_updates);
+            Ok(())
+        }
+        SlowConsumerPolicy::Disconnect if queued > threshold => {
+            if disconnect_with_error(client_profile, 0, ErrorCode::SlowConsumer)? {
+                Metrics::increment(&METRICS.slow_consumer_disconnects);
+            }
+
+            Ok(())
+        }
+        _ => send_data(client_profile, UPDATE_MESSAGE, 0, payload),
+    }
+}
+
+// Depth is dropped first and mini touch line last
+fn drop_priority(dtype: DataType) -> usize {
+    match dtype {
+        DataType::Depth => 0,
+        DataType::TouchLine => 1,
+        DataType::MiniTouchLine => 2,
+    }
+}
+
+// Discard pending output and tell client why it is disconnected
+// Client threadpool closes read side once error is written, client input then completes disconnection
+// Client which does not read error is dropped at its deadline
+// Returns false if client is being disconnected already
+pub fn disconnect_with_error(client_profile: &ClientProfile, request_id: u32, code: ErrorCode) -> io::Result<bool> {
+    let format = client_profile.session().format;
+
+    let json = JsonMessage::Error {
+        id: request_id,
+        code: code as u16,
+        message: code.message(),
+    };
+    let mut payload = [0; MESSAGE_BUF_SIZE];
+
+    let message = encode(format, &ErrorResponse::new(code), &json, &mut payload)
+        .and_then(|size| client_profile.frame(ERROR_RESPONSE, request_id, &payload[..size]))
+        .ok_or(io::ErrorKind::InvalidInput)?;
+
+    if !client_profile.output.close_with(message) {
+        return Ok(false);
+    }
+
+    client_profile.watch_deadline();
+    ClientThreadpool::flush(client_profile.handle);
+
+    Ok(true)
+}
+
+// Send market data over udp for clients which switched to it, otherwise over their connection
+// Live market data is not a response and carries request id 0
+pub fn send_data(client_profile: &ClientProfile, msg_type: u16, request_id: u32, payload: &[u8]) -> io::Result<()> {
+    let session = client_profile.session();
+
+    match session.mode {
+        Mode::Udp => {
+            // Waiting for udp switch
+            let Some(address) = session.udp_address else {
+                return Ok(());
+            };
+
+            let mut buffer = [0; size_of::<MessageHeader>() + MESSAGE_BUF_SIZE];
+
+            let Some(size) = write_message(msg_type, request_id, payload, &mut buffer) else {
+                return Err(io::ErrorKind::InvalidInput.into());
+            };
+
+            match UDP_SOCKET.send_to(&buffer[..size], address) {
+                // Datagrams are dropped when socket buffer is full
+                Err(e) if would_block(&e) => Ok(()),
+                result => result.map(|_| ()),
+            }
+        }
+        _ => client_profile.send(msg_type, request_id, payload),
+    }
+}
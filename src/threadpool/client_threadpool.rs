@@ -1,39 +1,48 @@
-use std::{
-    sync::{atomic::Ordering, Arc},
-    thread::JoinHandle,
-};
+use std::{net::Shutdown, sync::atomic::Ordering, thread::JoinHandle};
 
-use crate::types::work::ClientWork;
+use crate::{
+    globals::{CLIENTS_LIST, CLIENT_WORK_QUEUE},
+    types::{reuse_array::Handle, work::ClientWork},
+    utils::thread_utils::ThreadRole,
+};
 
-use super::ThreadPoolMaster;
-use crossbeam::queue::SegQueue;
+use super::{drain_scheduled, ThreadPoolMaster, WorkTrait};
 
-struct ClientThreadpool {
-    tpool_queue: Arc<SegQueue<ClientWork>>,
+pub struct ClientThreadpool {
     tpool: ThreadPoolMaster<ClientWork>,
 }
 
 impl ClientThreadpool {
     pub fn new(num_threads: usize) -> Self {
-        let tpool_queue = Arc::new(SegQueue::new());
-
         Self {
-            tpool_queue: tpool_queue.clone(),
-            tpool: ThreadPoolMaster::new(num_threads, tpool_queue),
+            tpool: ThreadPoolMaster::new(num_threads, CLIENT_WORK_QUEUE.clone(), ThreadRole::ClientOutput),
         }
     }
 
+    // Queue work of client, client is scheduled unless it is already scheduled or running
     pub fn do_work(work: ClientWork) {
+        let Some(client_profile) = CLIENTS_LIST.get(work.client) else {
+            return;
+        };
+
         // Add work to queue
-        work.client_profile.work_list.push(work);
+        client_profile.work_list.push(work);
 
         // Acquire lock
-        if work
-            .client_profile
-            .work_lock
-            .compare_exchange_weak(false, true, Ordering::Relaxed, Ordering::Relaxed)
-            .is_ok()
-        {}
+        if !client_profile.work_lock.swap(true, Ordering::SeqCst) {
+            CLIENT_WORK_QUEUE.push(ClientWork {
+                client: work.client,
+                processing_fn: process_client,
+            });
+        }
+    }
+
+    // Write queued output of client on a client thread
+    pub fn flush(client: Handle) {
+        Self::do_work(ClientWork {
+            client,
+            processing_fn: flush_client,
+        });
     }
 
     // Start threadpool
@@ -41,3 +50,31 @@ impl ClientThreadpool {
         self.tpool.start_tpool()
     }
 }
+
+// Failed connection is shut down, client input then completes disconnection
+// Closed output ends with an error for client, read side is closed only once all of it is written
+// Until then socket stays registered for writable events
+fn flush_client(work: &ClientWork) {
+    let Some(client_profile) = CLIENTS_LIST.get(work.client) else {
+        return;
+    };
+
+    if client_profile.flush().is_err() {
+        let _ = client_profile.conn().shutdown(Shutdown::Both);
+    } else if client_profile.output.is_finished() {
+        let _ = client_profile.conn().shutdown(Shutdown::Read);
+    }
+}
+
+// Run queued work of client in order
+// Only one thread runs a client at a time, different clients run in parallel
+fn process_client(work: &ClientWork) {
+    let Some(client_profile) = CLIENTS_LIST.get(work.client) else {
+        return;
+    };
+
+    // Client is kept alive even if it disconnects meanwhile
+    drain_scheduled(&client_profile.work_list, &client_profile.work_lock, |work| {
+        work.do_work()
+    });
+}
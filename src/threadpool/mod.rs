@@ -1,40 +1,91 @@
 pub mod client_threadpool;
+pub mod work_queue;
 
 use std::{
-    sync::Arc,
-    thread::{self, JoinHandle},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
 };
 
-use crossbeam::queue::SegQueue;
+use crossbeam::{queue::SegQueue, utils::Backoff};
 use threadpool::ThreadPool;
+use work_queue::WorkQueue;
+
+use crate::{
+    types::settings,
+    utils::thread_utils::{self, ThreadRole},
+};
 
 pub trait WorkTrait {
     fn do_work(&self);
 }
 
+// Process queue of work scheduled once per lock, lock is held by caller and released here
+// Items pushed after draining are processed here, unless they got scheduled again
+pub fn drain_scheduled<T>(queue: &SegQueue<T>, lock: &AtomicBool, mut process: impl FnMut(T)) {
+    loop {
+        while let Some(item) = queue.pop() {
+            process(item);
+        }
+
+        lock.store(false, Ordering::SeqCst);
+
+        if queue.is_empty() || lock.swap(true, Ordering::SeqCst) {
+            break;
+        }
+    }
+}
+
 pub struct ThreadPoolMaster<T: WorkTrait + 'static + Send + Sync> {
     pool: ThreadPool,
-    tpool_queue: Arc<SegQueue<T>>,
+    tpool_queue: Arc<WorkQueue<T>>,
+    role: ThreadRole,
 }
 
 unsafe impl<T: WorkTrait + Send + Sync> Send for ThreadPoolMaster<T> {}
 unsafe impl<T: WorkTrait + Send + Sync> Sync for ThreadPoolMaster<T> {}
 
 impl<T: WorkTrait + Send + Sync> ThreadPoolMaster<T> {
-    pub fn new(num_threads: usize, tpool_queue: Arc<SegQueue<T>>) -> Self {
-        let pool = ThreadPool::new(num_threads);
+    pub fn new(num_threads: usize, tpool_queue: Arc<WorkQueue<T>>, role: ThreadRole) -> Self {
+        let pool = threadpool::Builder::new()
+            .num_threads(num_threads)
+            .thread_name(role.name().to_string())
+            .build();
 
-        Self { pool, tpool_queue }
+        Self {
+            pool,
+            tpool_queue,
+            role,
+        }
     }
 
     pub fn start_tpool(&self) -> JoinHandle<()> {
         let tpool_queue = self.tpool_queue.clone();
         let pool = self.pool.clone();
 
-        thread::spawn(move || loop {
-            if let Some(work) = tpool_queue.pop() {
-                // Run in threadpool
-                pool.execute(move || work.do_work());
+        let strategy = settings::get().wait_strategy;
+        let role = self.role;
+
+        thread_utils::spawn_named(format!("{}-disp", role.name()), role, move || {
+            tpool_queue.set_dispatcher();
+
+            let backoff = Backoff::new();
+
+            loop {
+                match tpool_queue.pop() {
+                    Some(work) => {
+                        backoff.reset();
+
+                        // Run in threadpool
+                        pool.execute(move || {
+                            thread_utils::configure_once(role);
+                            work.do_work()
+                        });
+                    }
+                    None => tpool_queue.wait(strategy, &backoff),
+                }
             }
         })
     }
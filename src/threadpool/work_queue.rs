@@ -0,0 +1,80 @@
+use std::{
+    hint,
+    sync::{
+        atomic::{self, AtomicBool, Ordering},
+        OnceLock,
+    },
+    thread::{self, Thread},
+};
+
+use crossbeam::{queue::SegQueue, utils::Backoff};
+
+use crate::{
+    metrics::{Metrics, METRICS},
+    types::settings::WaitStrategy,
+};
+
+// Queue of work waiting for dispatcher, wakes it up when it is parked
+#[derive(Debug)]
+pub struct WorkQueue<T> {
+    queue: SegQueue<T>,
+    // Thread popping from queue
+    dispatcher: OnceLock<Thread>,
+    // Set while dispatcher is about to park or parked
+    parked: AtomicBool,
+}
+
+impl<T> WorkQueue<T> {
+    pub fn new() -> Self {
+        Self {
+            queue: SegQueue::new(),
+            dispatcher: OnceLock::new(),
+            parked: AtomicBool::new(false),
+        }
+    }
+
+    pub fn push(&self, work: T) {
+        self.queue.push(work);
+
+        // Make pushed work visible before checking if dispatcher sleeps
+        atomic::fence(Ordering::SeqCst);
+
+        if self.parked.load(Ordering::SeqCst) {
+            if let Some(dispatcher) = self.dispatcher.get() {
+                dispatcher.unpark();
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    // Register current thread as the one woken up on push
+    pub fn set_dispatcher(&self) {
+        let _ = self.dispatcher.set(thread::current());
+    }
+
+    // Wait for work after queue was found empty
+    // Backoff is reset by caller once work is found
+    pub fn wait(&self, strategy: WaitStrategy, backoff: &Backoff) {
+        match strategy {
+            WaitStrategy::BusySpin => hint::spin_loop(),
+            WaitStrategy::SpinYield => backoff.snooze(),
+            // Spin and yield for a while before going to sleep
+            WaitStrategy::Park if !backoff.is_completed() => backoff.snooze(),
+            WaitStrategy::Park => {
+                self.parked.store(true, Ordering::SeqCst);
+                atomic::fence(Ordering::SeqCst);
+
+                // Work pushed after this check unparks us, park then returns immediately
+                if self.queue.is_empty() {
+                    Metrics::increment(&METRICS.dispatcher_parks);
+                    thread::park();
+                }
+
+                self.parked.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+}
@@ -1,21 +1,71 @@
-use std::sync::{atomic::AtomicBool, Arc};
+use std::{
+    io::{self, Write},
+    mem::size_of,
+    net::{Shutdown, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
+    time::Instant,
+};
 
 use bitflags::bitflags;
 use crossbeam::queue::SegQueue;
-use mio::net::TcpStream;
-use tungstenite::WebSocket;
+use mio::{net::TcpStream, Interest, Token};
+use serde::Deserialize;
+use tungstenite::{
+    handshake::{server::NoCallback, MidHandshake},
+    Message, ServerHandshake, WebSocket,
+};
 
-use super::{settings::Mode, work::ClientWork};
+use crate::{
+    constants::{DISCONNECT_ERROR_TIMEOUT, MESSAGE_BUF_SIZE},
+    globals::{DEADLINE_CLIENTS, REGISTRY, WAKER},
+    threadpool::client_threadpool::ClientThreadpool,
+    utils::error_utils::{interrupted, would_block},
+};
 
-#[derive(Debug, Clone)]
+use super::{
+    outbound::{Outbound, OutboundQueue},
+    packet::InputPacket,
+    protocol::{write_message, MessageHeader},
+    reuse_array::Handle,
+    settings::{self, Mode, SlowConsumerPolicy},
+    work::ClientWork,
+};
+
+// Shared by client input, client threadpool and feed output threads, so all state is behind locks or atomics
+#[derive(Debug)]
 pub struct ClientProfile {
-    pub conn: Arc<Connection>,
-    pub subscriptions: Vec<ClientSubscription>,
+    // Slot in clients list, also token of connection in poll
+    pub handle: Handle,
+    // Locked by client input while reading and by the thread writing output
+    conn: Mutex<Connection>,
+    // Kind of connection never changes, so framing does not need its lock
+    websocket: bool,
+    pub session: RwLock<Session>,
+    // Only used by client input
+    pub subscriptions: Mutex<Vec<ClientSubscription>>,
+    // Partially received requests
+    pub input: Mutex<InputPacket>,
+    pub work_list: Arc<SegQueue<ClientWork>>,
+    pub work_lock: Arc<AtomicBool>,
+    // Messages waiting for socket to become writable
+    pub output: OutboundQueue,
+}
+
+// Negotiated at init, read by every thread sending to client
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
     pub mode: Mode,
     pub format: Format,
     pub initialized: bool,
-    pub work_list: Arc<SegQueue<ClientWork>>,
-    pub work_lock: Arc<AtomicBool>,
+    pub session_id: u64,
+    // Destination of market data in udp mode
+    pub udp_address: Option<SocketAddr>,
+    pub slow_consumer: SlowConsumerPolicy,
+    // Receives exchange wide messages
+    pub market_messages: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -24,7 +74,8 @@ pub struct ClientSubscription {
     pub dtype: TypeFlags,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Format {
     Json,
     Native,
@@ -33,10 +84,22 @@ pub enum Format {
 
 #[derive(Debug)]
 pub enum Connection {
-    Ws(WebSocket<TcpStream>),
+    Ws(Box<WsConnection>),
     Tcp(TcpStream),
 }
 
+// State of websocket connection, advanced on poll events
+#[derive(Debug)]
+pub enum WsConnection {
+    // Upgrade request not completed, dropped after deadline
+    Handshake(MidHandshake<ServerHandshake<TcpStream, NoCallback>>, Instant),
+    Open(WebSocket<TcpStream>),
+    // Close frame queued, waiting for peer till deadline
+    Closing(WebSocket<TcpStream>, Instant),
+    // Placeholder while moving between states
+    Closed,
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy)]
     pub struct TypeFlags: u8 {
@@ -49,15 +112,235 @@ bitflags! {
 }
 
 impl ClientProfile {
-    pub fn create_empty(conn: Arc<Connection>) -> Self {
+    pub fn create_empty(conn: Connection, handle: Handle) -> Self {
+        let websocket = matches!(conn, Connection::Ws(_));
+
         Self {
-            conn,
-            subscriptions: Vec::new(),
-            mode: Mode::default(),
-            format: Format::Native,
-            initialized: false,
+            handle,
+            websocket,
+            conn: Mutex::new(conn),
+            session: RwLock::new(Session {
+                mode: Mode::default(),
+                // Replies before init are json for websocket clients, as their requests are
+                format: if websocket { Format::Json } else { Format::Native },
+                initialized: false,
+                session_id: 0,
+                udp_address: None,
+                slow_consumer: settings::get().slow_consumer_policy,
+                market_messages: false,
+            }),
+            subscriptions: Mutex::new(Vec::new()),
+            input: Mutex::new(InputPacket::new()),
             work_list: Arc::new(SegQueue::new()),
             work_lock: Arc::new(AtomicBool::new(false)),
+            output: OutboundQueue::new(),
+        }
+    }
+
+    // Copy of session, it can change right after
+    pub fn session(&self) -> Session {
+        *self.session.read().unwrap()
+    }
+
+    pub fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Time after which client is dropped, if connection is stuck or error output is not read
+    pub fn deadline(&self) -> Option<Instant> {
+        let closing = self
+            .output
+            .closed_at()
+            .map(|closed_at| closed_at + DISCONNECT_ERROR_TIMEOUT);
+
+        self.conn().deadline().into_iter().chain(closing).min()
+    }
+
+    // Have client input watch deadline set outside of its own events
+    pub fn watch_deadline(&self) {
+        DEADLINE_CLIENTS.push(self.handle);
+
+        if let Some(waker) = WAKER.get() {
+            let _ = waker.wake();
+        }
+    }
+
+    // Queue message for client, it is written by client threadpool
+    pub fn send(&self, msg_type: u16, request_id: u32, payload: &[u8]) -> io::Result<()> {
+        let Some(message) = self.frame(msg_type, request_id, payload) else {
+            return Err(io::ErrorKind::InvalidInput.into());
+        };
+
+        self.output.push(message);
+        ClientThreadpool::flush(self.handle);
+
+        Ok(())
+    }
+
+    // Write queued messages, socket is watched for writable events while some are left
+    // Only one thread writes at a time, messages queued meanwhile are written by it
+    pub fn flush(&self) -> io::Result<()> {
+        loop {
+            let Ok(mut partial) = self.output.writer.try_lock() else {
+                return Ok(());
+            };
+
+            let mut conn = self.conn();
+            let pending = conn.write_queued(&self.output, &mut partial)?;
+
+            if pending != self.output.writable.load(Ordering::Relaxed) {
+                let interest = match pending {
+                    true => Interest::READABLE | Interest::WRITABLE,
+                    false => Interest::READABLE,
+                };
+
+                conn.reregister(Token(self.handle.into()), interest)?;
+                self.output.writable.store(pending, Ordering::Relaxed);
+            }
+
+            drop(conn);
+            drop(partial);
+
+            // Held back updates are written once client caught up
+            if !pending && self.output.release_conflated() {
+                continue;
+            }
+
+            // Recheck for messages queued while lock was held
+            if pending || self.output.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Frame message for kind of connection and format of client
+    // Returns None if it does not fit in buffer
+    pub fn frame(&self, msg_type: u16, request_id: u32, payload: &[u8]) -> Option<Outbound> {
+        let mut buffer = [0; size_of::<MessageHeader>() + MESSAGE_BUF_SIZE];
+        let size = write_message(msg_type, request_id, payload, &mut buffer)?;
+        let message = &buffer[..size];
+
+        let outbound = match (self.websocket, self.session().format) {
+            (false, _) => Outbound::Bytes(message.to_vec()),
+            (true, Format::Native) => Outbound::Ws(Message::Binary(message.to_vec())),
+            // Websocket frames json without header
+            (true, Format::Json | Format::JsonArray) => {
+                Outbound::Ws(Message::Text(String::from_utf8_lossy(payload).into_owned()))
+            }
+        };
+
+        Some(outbound)
+    }
+}
+
+impl Connection {
+    // Write queued messages until socket would block
+    // Returns true if data is left pending
+    fn write_queued(&mut self, output: &OutboundQueue, partial: &mut Option<(Vec<u8>, usize)>) -> io::Result<bool> {
+        match self {
+            Connection::Tcp(stream) => loop {
+                let (data, mut offset) = match partial.take() {
+                    Some(partial) => partial,
+                    None => match output.pop() {
+                        Some(Outbound::Bytes(data)) => (data, 0),
+                        Some(Outbound::Ws(_)) => continue,
+                        None => return Ok(false),
+                    },
+                };
+
+                while offset < data.len() {
+                    match stream.write(&data[offset..]) {
+                        Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                        Ok(size) => offset += size,
+                        Err(e) if interrupted(&e) => continue,
+                        Err(e) if would_block(&e) => {
+                            *partial = Some((data, offset));
+                            return Ok(true);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            },
+            Connection::Ws(ws) => {
+                // Nothing is sent before upgrade or after close
+                let WsConnection::Open(socket) = ws.as_mut() else {
+                    while output.pop().is_some() {}
+                    return Ok(false);
+                };
+
+                // Partial frames are kept by websocket, hand over next message only once it is flushed
+                loop {
+                    match socket.flush() {
+                        Ok(()) => {}
+                        Err(tungstenite::Error::Io(e)) if would_block(&e) => return Ok(true),
+                        Err(e) => return Err(io::Error::other(e)),
+                    }
+
+                    let message = match output.pop() {
+                        Some(Outbound::Ws(message)) => message,
+                        Some(Outbound::Bytes(_)) => continue,
+                        None => return Ok(false),
+                    };
+
+                    match socket.write(message) {
+                        // Message is buffered, flushed in next iteration
+                        Ok(()) => {}
+                        Err(tungstenite::Error::Io(e)) if would_block(&e) => {}
+                        Err(e) => return Err(io::Error::other(e)),
+                    }
+                }
+            }
+        }
+    }
+
+    // Change poll interest of socket
+    pub fn reregister(&mut self, token: Token, interest: Interest) -> io::Result<()> {
+        let Some(registry) = REGISTRY.get() else {
+            return Ok(());
+        };
+
+        let stream = match self {
+            Connection::Tcp(stream) => stream,
+            Connection::Ws(ws) => match ws.as_mut() {
+                WsConnection::Handshake(handshake, _) => handshake.get_mut().get_mut(),
+                WsConnection::Open(socket) | WsConnection::Closing(socket, _) => socket.get_mut(),
+                WsConnection::Closed => return Ok(()),
+            },
+        };
+
+        registry.reregister(stream, token, interest)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr(),
+            Connection::Ws(ws) => match ws.as_ref() {
+                WsConnection::Handshake(mid, _) => mid.get_ref().get_ref().peer_addr(),
+                WsConnection::Open(socket) | WsConnection::Closing(socket, _) => socket.get_ref().peer_addr(),
+                WsConnection::Closed => Err(io::ErrorKind::NotConnected.into()),
+            },
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(how),
+            Connection::Ws(ws) => match ws.as_ref() {
+                WsConnection::Handshake(handshake, _) => handshake.get_ref().get_ref().shutdown(how),
+                WsConnection::Open(socket) | WsConnection::Closing(socket, _) => socket.get_ref().shutdown(how),
+                WsConnection::Closed => Ok(()),
+            },
+        }
+    }
+
+    // Time after which connection is dropped, if it is waiting for handshake or close
+    pub fn deadline(&self) -> Option<Instant> {
+        match self {
+            Connection::Ws(ws) => match ws.as_ref() {
+                WsConnection::Handshake(_, deadline) | WsConnection::Closing(_, deadline) => Some(*deadline),
+                _ => None,
+            },
+            Connection::Tcp(_) => None,
         }
     }
 }
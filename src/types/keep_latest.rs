@@ -1,70 +1,171 @@
 use std::{
-    mem::zeroed,
-    ptr::{self, null_mut},
-    sync::{atomic::AtomicPtr, RwLock},
+    cell::UnsafeCell,
+    hint,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{fence, AtomicUsize, Ordering},
 };
 
+// Latest value of a type, written and read without locks or allocation (seqlock)
+// Sequence is odd while a write is in progress and 0 until first write
+// Readers copy value and retry if a write overlapped, so T must be plain data
+// which is valid for any bit pattern, torn copies are discarded
 pub struct KeepLatest<T> {
-    ptr: AtomicPtr<T>,
-    last: RwLock<*mut T>,
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
 }
 
-unsafe impl<T> Send for KeepLatest<T> {}
-unsafe impl<T> Sync for KeepLatest<T> {}
+unsafe impl<T: Send> Send for KeepLatest<T> {}
+unsafe impl<T: Send> Sync for KeepLatest<T> {}
 
-impl<T> KeepLatest<T> {
+impl<T: Copy> KeepLatest<T> {
     pub const fn new() -> Self {
         Self {
-            ptr: AtomicPtr::new(null_mut()),
-            last: RwLock::new(null_mut()),
+            seq: AtomicUsize::new(0),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
         }
     }
 
-    pub fn init(&self) {
-        *self.last.write().unwrap() = Box::into_raw(Box::new(unsafe { zeroed() }));
-    }
-
-    pub fn get(&self, data: &mut T) {
-        let ptr = self.ptr.swap(null_mut(), std::sync::atomic::Ordering::SeqCst);
+    // Copy latest value to data
+    // Returns false if nothing was written yet
+    pub fn get(&self, data: &mut T) -> bool {
+        loop {
+            let start = self.seq.load(Ordering::Acquire);
 
-        if ptr.is_null() {
-            let last_ptr = *self.last.read().unwrap();
+            // Never written
+            if start == 0 {
+                return false;
+            }
 
-            unsafe {
-                ptr::copy_nonoverlapping(last_ptr, data, 1);
+            // Writer is in the middle of copying
+            if start & 1 == 1 {
+                hint::spin_loop();
+                continue;
             }
-        } else {
-            // memcpy to last ptr
-            let ptr_last = self.last.write().unwrap();
-            unsafe {
-                ptr::copy_nonoverlapping(ptr, *ptr_last, 1);
-                drop(ptr_last);
-
-                // Copy from ptr to data
-                ptr::copy_nonoverlapping(ptr, data, 1);
-
-                // delete ptr
-                let _ = Box::from_raw(ptr);
+
+            // SAFETY: value was written once sequence is non zero, torn reads are discarded below
+            let value = unsafe { ptr::read_volatile(self.value.get()) };
+
+            fence(Ordering::Acquire);
+
+            if self.seq.load(Ordering::Relaxed) == start {
+                // SAFETY: sequence did not change, so value was fully written
+                *data = unsafe { value.assume_init() };
+                return true;
             }
         }
     }
 
-    pub fn write(&self, data: T) -> bool {
-        let mut consumed = false;
-        let new_ptr = Box::into_raw(Box::new(data));
-
-        // Assign new_ptr to ptr
-        let old_ptr = self.ptr.swap(new_ptr, std::sync::atomic::Ordering::SeqCst);
+    pub fn write(&self, data: T) {
+        // Writers take turns by moving sequence to odd
+        let start = loop {
+            let seq = self.seq.load(Ordering::Relaxed);
 
-        // Mark consumed true if old_ptr is null
-        if old_ptr.is_null() {
-            consumed = true;
-        } else {
-            unsafe {
-                let _ = Box::from_raw(old_ptr);
+            if seq & 1 == 0
+                && self
+                    .seq
+                    .compare_exchange_weak(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                break seq;
             }
+
+            hint::spin_loop();
+        };
+
+        // Readers seeing any part of new value also see odd sequence
+        fence(Ordering::Release);
+
+        // SAFETY: only this writer holds odd sequence
+        unsafe { ptr::write_volatile(self.value.get(), MaybeUninit::new(data)) };
+
+        self.seq.store(start + 2, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::KeepLatest;
+
+    // Large enough to be copied in several steps
+    type Value = [u64; 64];
+
+    #[test]
+    fn empty_until_written() {
+        let latest = KeepLatest::<Value>::new();
+        let mut data = [7; 64];
+
+        assert!(!latest.get(&mut data));
+        assert_eq!(data, [7; 64]);
+
+        latest.write([1; 64]);
+        latest.write([2; 64]);
+
+        assert!(latest.get(&mut data));
+        assert_eq!(data, [2; 64]);
+
+        // Value is kept after reading
+        assert!(latest.get(&mut data));
+        assert_eq!(data, [2; 64]);
+    }
+
+    #[test]
+    fn readers_never_see_torn_values() {
+        let latest = Arc::new(KeepLatest::<Value>::new());
+        let writers = 4;
+        let writes = 20_000;
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let latest = latest.clone();
+
+                thread::spawn(move || {
+                    let mut data = [0; 64];
+                    let mut last = [0; 4];
+
+                    while data[0] != u64::MAX {
+                        if !latest.get(&mut data) {
+                            continue;
+                        }
+
+                        // Writer id in high bits, write number in low bits
+                        assert!(data.iter().all(|&v| v == data[0]), "torn read {:?}", data);
+
+                        if data[0] == u64::MAX {
+                            break;
+                        }
+
+                        // Writes of each writer are seen in order
+                        let writer = (data[0] >> 32) as usize;
+                        assert!(data[0] >= last[writer]);
+                        last[writer] = data[0];
+                    }
+                })
+            })
+            .collect();
+
+        let handles: Vec<_> = (0..writers)
+            .map(|writer| {
+                let latest = latest.clone();
+
+                thread::spawn(move || {
+                    for i in 1..=writes {
+                        latest.write([((writer as u64) << 32) | i; 64]);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
         }
 
-        consumed
+        latest.write([u64::MAX; 64]);
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
     }
 }
@@ -0,0 +1,324 @@
+use std::mem::size_of;
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+    constants::{DEPTH_LEVELS, FEED_DEPTH, FEED_MINI_TOUCH_LINE, FEED_TOUCH_LINE},
+    utils::byte_utils::{parse_struct, struct_to_bytes},
+};
+
+use super::{keep_latest::KeepLatest, packet::FeedHeader, protocol::DataType};
+
+// Layouts follow feed header in packets and native messages
+// Prices are in smallest price unit, times in nanoseconds since epoch
+// Fields are ordered so structs have no padding
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct DepthLevel {
+    pub price: i64,
+    pub quantity: u64,
+}
+
+// Full market by price, lighter views can be derived from it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Depth {
+    pub exchange_time: u64,
+    pub last_trade_time: u64,
+    pub ltp: i64,
+    pub ltq: u64,
+    pub volume: u64,
+    pub open: i64,
+    pub high: i64,
+    pub low: i64,
+    pub close: i64,
+    pub open_interest: u64,
+    // Best price first
+    pub bids: [DepthLevel; DEPTH_LEVELS],
+    pub asks: [DepthLevel; DEPTH_LEVELS],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TouchLine {
+    pub exchange_time: u64,
+    pub last_trade_time: u64,
+    pub ltp: i64,
+    pub ltq: u64,
+    pub volume: u64,
+    pub open: i64,
+    pub high: i64,
+    pub low: i64,
+    pub close: i64,
+    pub open_interest: u64,
+    pub best_bid: DepthLevel,
+    pub best_ask: DepthLevel,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MiniTouchLine {
+    pub exchange_time: u64,
+    pub ltp: i64,
+    pub ltq: u64,
+    pub volume: u64,
+    pub best_bid_price: i64,
+    pub best_ask_price: i64,
+}
+
+// Parsed token wise update
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "dtype", content = "data", rename_all = "snake_case")]
+pub enum MarketData {
+    Depth(Depth),
+    TouchLine(TouchLine),
+    MiniTouchLine(MiniTouchLine),
+}
+
+// Fields in declaration order, for json array clients
+pub struct Positional<'a>(pub &'a MarketData);
+
+// Latest market data of each type for a token
+pub struct TokenData {
+    depth: KeepLatest<Depth>,
+    touch_line: KeepLatest<TouchLine>,
+    mini_touch_line: KeepLatest<MiniTouchLine>,
+}
+
+impl From<&Depth> for TouchLine {
+    fn from(depth: &Depth) -> Self {
+        Self {
+            exchange_time: depth.exchange_time,
+            last_trade_time: depth.last_trade_time,
+            ltp: depth.ltp,
+            ltq: depth.ltq,
+            volume: depth.volume,
+            open: depth.open,
+            high: depth.high,
+            low: depth.low,
+            close: depth.close,
+            open_interest: depth.open_interest,
+            best_bid: depth.bids[0],
+            best_ask: depth.asks[0],
+        }
+    }
+}
+
+impl From<&Depth> for MiniTouchLine {
+    fn from(depth: &Depth) -> Self {
+        Self {
+            exchange_time: depth.exchange_time,
+            ltp: depth.ltp,
+            ltq: depth.ltq,
+            volume: depth.volume,
+            best_bid_price: depth.bids[0].price,
+            best_ask_price: depth.asks[0].price,
+        }
+    }
+}
+
+impl MarketData {
+    // Parse body of feed packet
+    // Returns None if its size does not match type
+    pub fn parse(dtype: DataType, body: &[u8]) -> Option<Self> {
+        match dtype {
+            DataType::Depth => parse_struct(body).map(MarketData::Depth),
+            DataType::TouchLine => parse_struct(body).map(MarketData::TouchLine),
+            DataType::MiniTouchLine => parse_struct(body).map(MarketData::MiniTouchLine),
+        }
+    }
+
+    // Lighter views of depth, other types have none
+    pub fn derived(&self) -> Option<[MarketData; 2]> {
+        match self {
+            MarketData::Depth(depth) => Some([
+                MarketData::TouchLine(depth.into()),
+                MarketData::MiniTouchLine(depth.into()),
+            ]),
+            _ => None,
+        }
+    }
+
+    pub fn dtype(&self) -> DataType {
+        match self {
+            MarketData::Depth(_) => DataType::Depth,
+            MarketData::TouchLine(_) => DataType::TouchLine,
+            MarketData::MiniTouchLine(_) => DataType::MiniTouchLine,
+        }
+    }
+
+    // Write feed packet of token, as received by native clients
+    // Returns written size or None if it does not fit in buffer
+    pub fn write_packet(&self, token: u32, buffer: &mut [u8]) -> Option<usize> {
+        let (msg_type, body_size) = match self {
+            MarketData::Depth(_) => (FEED_DEPTH, size_of::<Depth>()),
+            MarketData::TouchLine(_) => (FEED_TOUCH_LINE, size_of::<TouchLine>()),
+            MarketData::MiniTouchLine(_) => (FEED_MINI_TOUCH_LINE, size_of::<MiniTouchLine>()),
+        };
+
+        let header_size = size_of::<FeedHeader>();
+        let size = header_size + body_size;
+
+        if buffer.len() < size {
+            return None;
+        }
+
+        let header = FeedHeader {
+            msg_type,
+            length: size as u16,
+            token,
+        };
+
+        struct_to_bytes(&header, buffer);
+
+        let body = &mut buffer[header_size..];
+
+        match self {
+            MarketData::Depth(depth) => struct_to_bytes(depth, body),
+            MarketData::TouchLine(touch_line) => struct_to_bytes(touch_line, body),
+            MarketData::MiniTouchLine(mini_touch_line) => struct_to_bytes(mini_touch_line, body),
+        }
+
+        Some(size)
+    }
+}
+
+impl Serialize for Positional<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let level = |level: DepthLevel| (level.price, level.quantity);
+
+        match self.0 {
+            MarketData::Depth(depth) => (
+                depth.exchange_time,
+                depth.last_trade_time,
+                depth.ltp,
+                depth.ltq,
+                depth.volume,
+                depth.open,
+                depth.high,
+                depth.low,
+                depth.close,
+                depth.open_interest,
+                depth.bids.map(level),
+                depth.asks.map(level),
+            )
+                .serialize(serializer),
+            MarketData::TouchLine(touch_line) => (
+                touch_line.exchange_time,
+                touch_line.last_trade_time,
+                touch_line.ltp,
+                touch_line.ltq,
+                touch_line.volume,
+                touch_line.open,
+                touch_line.high,
+                touch_line.low,
+                touch_line.close,
+                touch_line.open_interest,
+                level(touch_line.best_bid),
+                level(touch_line.best_ask),
+            )
+                .serialize(serializer),
+            MarketData::MiniTouchLine(mini_touch_line) => (
+                mini_touch_line.exchange_time,
+                mini_touch_line.ltp,
+                mini_touch_line.ltq,
+                mini_touch_line.volume,
+                mini_touch_line.best_bid_price,
+                mini_touch_line.best_ask_price,
+            )
+                .serialize(serializer),
+        }
+    }
+}
+
+impl TokenData {
+    pub const fn new() -> Self {
+        Self {
+            depth: KeepLatest::new(),
+            touch_line: KeepLatest::new(),
+            mini_touch_line: KeepLatest::new(),
+        }
+    }
+
+    pub fn write(&self, data: MarketData) {
+        match data {
+            MarketData::Depth(depth) => self.depth.write(depth),
+            MarketData::TouchLine(touch_line) => self.touch_line.write(touch_line),
+            MarketData::MiniTouchLine(mini_touch_line) => self.mini_touch_line.write(mini_touch_line),
+        }
+    }
+
+    // Whether data differs from latest value of its type
+    // Exchange time is ignored, it moves with every depth update
+    pub fn changed(&self, data: &MarketData) -> bool {
+        let Some(mut latest) = self.get(data.dtype()) else {
+            return true;
+        };
+
+        match (&mut latest, data) {
+            (MarketData::Depth(latest), MarketData::Depth(data)) => latest.exchange_time = data.exchange_time,
+            (MarketData::TouchLine(latest), MarketData::TouchLine(data)) => latest.exchange_time = data.exchange_time,
+            (MarketData::MiniTouchLine(latest), MarketData::MiniTouchLine(data)) => {
+                latest.exchange_time = data.exchange_time
+            }
+            _ => {}
+        }
+
+        latest != *data
+    }
+
+    // Returns None if nothing was written yet
+    pub fn get(&self, dtype: DataType) -> Option<MarketData> {
+        match dtype {
+            DataType::Depth => {
+                let mut depth = Depth::default();
+                self.depth.get(&mut depth).then_some(MarketData::Depth(depth))
+            }
+            DataType::TouchLine => {
+                let mut touch_line = TouchLine::default();
+                self.touch_line
+                    .get(&mut touch_line)
+                    .then_some(MarketData::TouchLine(touch_line))
+            }
+            DataType::MiniTouchLine => {
+                let mut mini_touch_line = MiniTouchLine::default();
+                self.mini_touch_line
+                    .get(&mut mini_touch_line)
+                    .then_some(MarketData::MiniTouchLine(mini_touch_line))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Depth, MarketData, TokenData};
+
+    #[test]
+    fn derived_views_change_only_with_their_fields() {
+        let store = TokenData::new();
+        let mut depth = Depth {
+            ltp: 100,
+            ..Default::default()
+        };
+
+        let [touch_line, mini_touch_line] = MarketData::Depth(depth).derived().unwrap();
+        assert!(store.changed(&touch_line));
+        store.write(touch_line);
+        store.write(mini_touch_line);
+
+        // Time and deeper levels are not part of lighter views
+        depth.exchange_time = 1;
+        depth.bids[1].quantity = 5;
+        let [touch_line, mini_touch_line] = MarketData::Depth(depth).derived().unwrap();
+        assert!(!store.changed(&touch_line));
+        assert!(!store.changed(&mini_touch_line));
+
+        // Open interest is only part of touch line
+        depth.open_interest = 7;
+        let [touch_line, mini_touch_line] = MarketData::Depth(depth).derived().unwrap();
+        assert!(store.changed(&touch_line));
+        assert!(!store.changed(&mini_touch_line));
+    }
+}
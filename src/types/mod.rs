@@ -1,7 +1,11 @@
 pub mod client_profile;
 pub mod keep_latest;
+pub mod market_data;
+pub mod outbound;
 pub mod packet;
+pub mod protocol;
 pub mod reuse_array;
 pub mod settings;
 pub mod subscription;
+pub mod token_universe;
 pub mod work;
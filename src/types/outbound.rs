@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, OnceLock, PoisonError,
+    },
+    time::Instant,
+};
+
+use crossbeam::queue::SegQueue;
+use tungstenite::Message;
+
+use super::protocol::DataType;
+
+// Message waiting to be written to client connection
+#[derive(Debug)]
+pub enum Outbound {
+    // Framed message for tcp connections
+    Bytes(Vec<u8>),
+    Ws(Message),
+}
+
+// Messages of a client waiting to be written to its socket
+#[derive(Debug)]
+pub struct OutboundQueue {
+    queue: SegQueue<Outbound>,
+    queued_bytes: AtomicUsize,
+    // Latest update per token and type, held back while client is slow
+    conflated: Mutex<HashMap<(u32, DataType), Outbound>>,
+    // Set once client is being disconnected, nothing is queued after it
+    closed: AtomicBool,
+    closed_at: OnceLock<Instant>,
+    // Held by thread writing to socket, keeps rest of partially written message
+    pub writer: Mutex<Option<(Vec<u8>, usize)>>,
+    // Socket is registered for writable events
+    pub writable: AtomicBool,
+}
+
+impl Outbound {
+    pub fn size(&self) -> usize {
+        match self {
+            Outbound::Bytes(bytes) => bytes.len(),
+            Outbound::Ws(message) => message.len(),
+        }
+    }
+}
+
+impl OutboundQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: SegQueue::new(),
+            queued_bytes: AtomicUsize::new(0),
+            conflated: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            closed_at: OnceLock::new(),
+            writer: Mutex::new(None),
+            writable: AtomicBool::new(false),
+        }
+    }
+
+    pub fn push(&self, message: Outbound) {
+        if !self.closed.load(Ordering::Relaxed) {
+            self.enqueue(message);
+        }
+    }
+
+    pub fn pop(&self) -> Option<Outbound> {
+        let message = self.queue.pop()?;
+        self.queued_bytes.fetch_sub(message.size(), Ordering::Relaxed);
+
+        Some(message)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    // Size of messages not handed to socket yet
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    // Queue update unless updates are being conflated
+    // Returns true if update replaced or got held back instead of being queued
+    pub fn push_update(&self, token: u32, dtype: DataType, message: Outbound, conflate: bool) -> bool {
+        let mut conflated = self.conflated.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Held back updates go first, so newer ones wait behind them
+        if !conflate && conflated.is_empty() {
+            self.push(message);
+            return false;
+        }
+
+        conflated.insert((token, dtype), message);
+
+        true
+    }
+
+    // Move held back updates to queue
+    // Returns false if there were none
+    pub fn release_conflated(&self) -> bool {
+        let mut conflated = self.conflated.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if conflated.is_empty() {
+            return false;
+        }
+
+        for (_, message) in conflated.drain() {
+            self.push(message);
+        }
+
+        true
+    }
+
+    // Replace pending messages with last message and stop accepting more
+    // Partially written message is kept, so last message is framed correctly
+    // Returns false if queue was closed already
+    pub fn close_with(&self, message: Outbound) -> bool {
+        // Writer sees queue either open or closed with last message in it
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        let _ = self.closed_at.set(Instant::now());
+
+        self.conflated.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        while self.pop().is_some() {}
+
+        self.enqueue(message);
+
+        true
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn closed_at(&self) -> Option<Instant> {
+        self.closed_at.get().copied()
+    }
+
+    // Closed and last message fully handed to socket
+    pub fn is_finished(&self) -> bool {
+        let partial = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+
+        self.is_closed() && partial.is_none() && self.is_empty()
+    }
+
+    fn enqueue(&self, message: Outbound) {
+        self.queued_bytes.fetch_add(message.size(), Ordering::Relaxed);
+        self.queue.push(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::protocol::DataType;
+
+    use super::{Outbound, OutboundQueue};
+
+    fn message(id: u8) -> Outbound {
+        Outbound::Bytes(vec![id])
+    }
+
+    fn drain(queue: &OutboundQueue) -> Vec<u8> {
+        let mut ids = Vec::new();
+
+        while let Some(message) = queue.pop() {
+            match message {
+                Outbound::Bytes(bytes) => ids.push(bytes[0]),
+                Outbound::Ws(_) => unreachable!(),
+            }
+        }
+
+        ids
+    }
+
+    #[test]
+    fn held_back_updates_replace_each_other() {
+        let queue = OutboundQueue::new();
+
+        assert!(queue.push_update(1, DataType::Depth, message(1), true));
+        assert!(queue.push_update(1, DataType::Depth, message(2), true));
+        assert!(queue.push_update(1, DataType::TouchLine, message(3), true));
+        assert!(queue.push_update(2, DataType::Depth, message(4), true));
+
+        assert!(queue.is_empty());
+        assert_eq!(queue.queued_bytes(), 0);
+
+        assert!(queue.release_conflated());
+
+        let mut ids = drain(&queue);
+        ids.sort_unstable();
+
+        // Only latest update per token and type is left
+        assert_eq!(ids, [2, 3, 4]);
+        assert!(!queue.release_conflated());
+    }
+
+    #[test]
+    fn updates_wait_behind_held_back_ones() {
+        let queue = OutboundQueue::new();
+
+        assert!(!queue.push_update(1, DataType::Depth, message(1), false));
+        assert!(queue.push_update(2, DataType::Depth, message(2), true));
+
+        // Not conflating anymore, but held back updates go first
+        assert!(queue.push_update(3, DataType::Depth, message(3), false));
+        assert_eq!(drain(&queue), [1]);
+
+        assert!(queue.release_conflated());
+        queue.push(message(4));
+
+        let ids = drain(&queue);
+        let (released, later) = ids.split_at(2);
+
+        assert!(released.contains(&2) && released.contains(&3));
+        assert_eq!(later, [4]);
+
+        // Queue goes back to plain ordering once drained
+        assert!(!queue.push_update(1, DataType::Depth, message(5), false));
+        assert_eq!(drain(&queue), [5]);
+    }
+
+    #[test]
+    fn close_discards_pending_output() {
+        let queue = OutboundQueue::new();
+
+        queue.push(message(1));
+        queue.push_update(1, DataType::Depth, message(2), true);
+
+        assert!(queue.close_with(message(3)));
+        assert!(queue.is_closed());
+        assert_eq!(queue.queued_bytes(), 1);
+
+        // Nothing is queued after closing message
+        queue.push(message(4));
+        queue.push_update(1, DataType::Depth, message(5), false);
+        assert!(!queue.close_with(message(6)));
+        queue.release_conflated();
+
+        assert_eq!(drain(&queue), [3]);
+        assert_eq!(queue.queued_bytes(), 0);
+    }
+
+    #[test]
+    fn finished_once_last_message_is_written() {
+        let queue = OutboundQueue::new();
+
+        // Message cut short by a full socket
+        *queue.writer.lock().unwrap() = Some((vec![1, 2], 1));
+
+        assert!(queue.close_with(message(3)));
+        assert!(queue.closed_at().is_some());
+        assert!(!queue.is_finished());
+
+        assert_eq!(drain(&queue), [3]);
+        assert!(!queue.is_finished());
+
+        queue.writer.lock().unwrap().take();
+        assert!(queue.is_finished());
+    }
+}
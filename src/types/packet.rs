@@ -1,6 +1,8 @@
-use crate::constants::{INPUT_BUF_SIZE, OUTPUT_BUF_SIZE};
+use crate::constants::{FEED_DEPTH, FEED_MINI_TOUCH_LINE, FEED_TOUCH_LINE, INPUT_BUF_SIZE, OUTPUT_BUF_SIZE};
 
-#[derive(Clone, Copy)]
+use super::protocol::DataType;
+
+#[derive(Debug, Clone, Copy)]
 pub struct OutputPacket(pub [u8; OUTPUT_BUF_SIZE], pub usize);
 
 impl OutputPacket {
@@ -9,7 +11,7 @@ impl OutputPacket {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct InputPacket(pub [u8; INPUT_BUF_SIZE], pub usize);
 
 impl InputPacket {
@@ -17,3 +19,24 @@ impl InputPacket {
         Self([0; INPUT_BUF_SIZE], 0)
     }
 }
+
+// Header at the start of every feed packet
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FeedHeader {
+    pub msg_type: u16,
+    pub length: u16,
+    pub token: u32,
+}
+
+impl FeedHeader {
+    // Type of token wise data, None for market messages
+    pub fn data_type(&self) -> Option<DataType> {
+        match self.msg_type {
+            FEED_DEPTH => Some(DataType::Depth),
+            FEED_TOUCH_LINE => Some(DataType::TouchLine),
+            FEED_MINI_TOUCH_LINE => Some(DataType::MiniTouchLine),
+            _ => None,
+        }
+    }
+}
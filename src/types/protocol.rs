@@ -0,0 +1,445 @@
+use std::{
+    mem::size_of,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+
+use crate::{constants::ERROR_MESSAGE_SIZE, utils::byte_utils::struct_to_bytes};
+
+use super::{
+    client_profile::{ClientSubscription, Format, TypeFlags},
+    settings::{Mode, SlowConsumerPolicy},
+};
+
+// Header of every framed message on tcp connections
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MessageHeader {
+    pub msg_type: u16,
+    // Length of payload following the header
+    pub length: u16,
+    // Chosen by client, echoed in responses to the request
+    pub request_id: u32,
+}
+
+// Payload entry of subscribe and unsubscribe requests
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TokenRequest {
+    pub token: u32,
+    pub dtype: u32,
+}
+
+// Payload of init request
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NativeInitRequest {
+    pub version: u16,
+    pub format: u8,
+    pub mode: u8,
+    // Bitwise or of INIT_FLAG values, older clients leave it out
+    pub flags: u16,
+}
+
+// Receive market messages along with last ones sent before init
+pub const INIT_FLAG_MARKET_MESSAGES: u16 = 1;
+
+// Payload of udp switch request
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NativeUdpSwitchRequest {
+    pub ip: [u8; 4],
+    pub port: u16,
+}
+
+// Payload of init response
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct InitResponse {
+    pub session_id: u64,
+    pub protocol_version: u16,
+    pub server_version: [u16; 3],
+    pub max_subscriptions: u32,
+    pub max_request_size: u32,
+}
+
+// Payload of error response
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorResponse {
+    pub code: u16,
+    // Null padded
+    pub message: [u8; ERROR_MESSAGE_SIZE],
+}
+
+// Payload of acknowledgement, followed by count token acks
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AckHeader {
+    pub count: u16,
+    // 1 if more acknowledgements follow for the same request
+    pub more: u16,
+}
+
+// Result of single token in acknowledgement, code is 0 when accepted
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TokenAck {
+    pub token: u32,
+    pub code: u32,
+}
+
+// Outcome of single token of subscribe and unsubscribe requests
+#[derive(Debug, Clone, Copy)]
+pub struct TokenResult {
+    pub token: usize,
+    pub error: Option<ErrorCode>,
+}
+
+// Reason of rejected request, values are part of the protocol
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    UnknownRequest = 1,
+    MalformedRequest = 2,
+    NotInitialized = 3,
+    AlreadyInitialized = 4,
+    UnsupportedVersion = 5,
+    UnsupportedMode = 6,
+    InvalidToken = 7,
+    InvalidType = 8,
+    LimitExceeded = 9,
+    InvalidAddress = 10,
+    NotSubscribed = 11,
+    SlowConsumer = 12,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct InitRequest {
+    pub version: u16,
+    #[serde(default = "default_json_format")]
+    pub format: Format,
+    #[serde(default)]
+    pub mode: Mode,
+    // Server default is used when missing, native clients always use it
+    #[serde(default)]
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+    #[serde(default)]
+    pub market_messages: bool,
+}
+
+// Request decoded from any client connection
+#[derive(Debug, Clone)]
+pub enum Request {
+    Init(InitRequest),
+    Subscribe(Vec<ClientSubscription>),
+    Unsubscribe(Vec<ClientSubscription>),
+    UdpSwitch(SocketAddr),
+    Invalid(ErrorCode),
+}
+
+// Json request with its id
+#[derive(Debug, Deserialize)]
+struct JsonEnvelope {
+    #[serde(default)]
+    id: u32,
+    #[serde(flatten)]
+    request: JsonRequest,
+}
+
+// Used to recover id of malformed json request
+#[derive(Debug, Deserialize)]
+struct JsonId {
+    #[serde(default)]
+    id: u32,
+}
+
+// Request sent as json by websocket clients
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JsonRequest {
+    Init(InitRequest),
+    Subscribe {
+        tokens: Vec<usize>,
+        #[serde(default)]
+        types: Vec<JsonDataType>,
+    },
+    Unsubscribe {
+        tokens: Vec<usize>,
+        #[serde(default)]
+        types: Vec<JsonDataType>,
+    },
+    UdpSwitch {
+        address: SocketAddr,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+// Keeps unknown type names to report them instead of failing whole request
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum JsonDataType {
+    Known(DataType),
+    Unknown(IgnoredAny),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DataType {
+    Depth,
+    TouchLine,
+    MiniTouchLine,
+}
+
+impl DataType {
+    pub const ALL: [DataType; 3] = [DataType::Depth, DataType::TouchLine, DataType::MiniTouchLine];
+}
+
+impl From<DataType> for TypeFlags {
+    fn from(dtype: DataType) -> Self {
+        match dtype {
+            DataType::Depth => TypeFlags::DEPTH,
+            DataType::TouchLine => TypeFlags::TOUCH_LINE,
+            DataType::MiniTouchLine => TypeFlags::MINI_TOUCH_LINE,
+        }
+    }
+}
+
+impl From<JsonRequest> for Request {
+    fn from(request: JsonRequest) -> Self {
+        let result = match request {
+            JsonRequest::Init(init) => Ok(Request::Init(init)),
+            JsonRequest::Subscribe { tokens, types } => to_subscriptions(tokens, &types).map(Request::Subscribe),
+            JsonRequest::Unsubscribe { tokens, types } => to_subscriptions(tokens, &types).map(Request::Unsubscribe),
+            JsonRequest::UdpSwitch { address } => Ok(Request::UdpSwitch(address)),
+            JsonRequest::Unknown => Err(ErrorCode::UnknownRequest),
+        };
+
+        result.unwrap_or_else(Request::Invalid)
+    }
+}
+
+impl ErrorCode {
+    pub fn message(self) -> &'static str {
+        match self {
+            ErrorCode::UnknownRequest => "unknown request type",
+            ErrorCode::MalformedRequest => "malformed request",
+            ErrorCode::NotInitialized => "session is not initialized",
+            ErrorCode::AlreadyInitialized => "session is already initialized",
+            ErrorCode::UnsupportedVersion => "unsupported protocol version",
+            ErrorCode::UnsupportedMode => "unsupported mode",
+            ErrorCode::InvalidToken => "token out of range",
+            ErrorCode::InvalidType => "unknown data type",
+            ErrorCode::LimitExceeded => "subscription limit exceeded",
+            ErrorCode::InvalidAddress => "udp address does not match client host",
+            ErrorCode::NotSubscribed => "token is not subscribed",
+            ErrorCode::SlowConsumer => "client is not reading fast enough",
+        }
+    }
+}
+
+impl TokenResult {
+    pub fn accepted(token: usize) -> Self {
+        Self { token, error: None }
+    }
+
+    pub fn rejected(token: usize, code: ErrorCode) -> Self {
+        Self {
+            token,
+            error: Some(code),
+        }
+    }
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode) -> Self {
+        let mut message = [0; ERROR_MESSAGE_SIZE];
+        let text = code.message().as_bytes();
+        let size = text.len().min(ERROR_MESSAGE_SIZE);
+
+        message[..size].copy_from_slice(&text[..size]);
+
+        Self {
+            code: code as u16,
+            message,
+        }
+    }
+}
+
+impl NativeInitRequest {
+    pub fn decode(&self) -> Option<InitRequest> {
+        let format = match self.format {
+            0 => Format::Native,
+            1 => Format::Json,
+            2 => Format::JsonArray,
+            _ => return None,
+        };
+
+        let mode = match self.mode {
+            0 => Mode::Tcp,
+            1 => Mode::Udp,
+            _ => return None,
+        };
+
+        Some(InitRequest {
+            version: self.version,
+            format,
+            mode,
+            slow_consumer: None,
+            market_messages: self.flags & INIT_FLAG_MARKET_MESSAGES != 0,
+        })
+    }
+}
+
+impl NativeUdpSwitchRequest {
+    pub fn decode(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(self.ip), self.port))
+    }
+}
+
+impl Request {
+    // Returns request with its id, id is 0 if missing
+    pub fn from_json(data: &[u8]) -> (u32, Self) {
+        match serde_json::from_slice::<JsonEnvelope>(data) {
+            Ok(envelope) => (envelope.id, envelope.request.into()),
+            Err(_) => {
+                let id = serde_json::from_slice::<JsonId>(data).map_or(0, |json_id| json_id.id);
+
+                (id, Request::Invalid(ErrorCode::MalformedRequest))
+            }
+        }
+    }
+}
+
+// Missing types mean all types
+fn to_subscriptions(tokens: Vec<usize>, types: &[JsonDataType]) -> Result<Vec<ClientSubscription>, ErrorCode> {
+    let mut dtype = TypeFlags::empty();
+
+    for json_dtype in types {
+        match json_dtype {
+            JsonDataType::Known(known) => dtype |= (*known).into(),
+            JsonDataType::Unknown(_) => return Err(ErrorCode::InvalidType),
+        }
+    }
+
+    if dtype.is_empty() {
+        dtype = TypeFlags::ALL;
+    }
+
+    let subscriptions = tokens
+        .into_iter()
+        .map(|token| ClientSubscription { token, dtype })
+        .collect();
+
+    Ok(subscriptions)
+}
+
+// Write header and payload to buffer
+// Returns message size or None if it does not fit in buffer
+pub fn write_message(msg_type: u16, request_id: u32, payload: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    let header_size = size_of::<MessageHeader>();
+    let size = header_size + payload.len();
+
+    if size > buffer.len() || payload.len() > u16::MAX as usize {
+        return None;
+    }
+
+    let header = MessageHeader {
+        msg_type,
+        length: payload.len() as u16,
+        request_id,
+    };
+
+    struct_to_bytes(&header, buffer);
+    buffer[header_size..size].copy_from_slice(payload);
+
+    Some(size)
+}
+
+// Websocket clients expect json unless asked otherwise
+fn default_json_format() -> Format {
+    Format::Json
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{
+        client_profile::{Format, TypeFlags},
+        settings::Mode,
+    };
+
+    use super::{ErrorCode, Request};
+
+    fn parse(json: &str) -> (u32, Request) {
+        Request::from_json(json.as_bytes())
+    }
+
+    #[test]
+    fn init_fills_in_defaults() {
+        let (id, request) = parse(r#"{"op": "init", "id": 1, "version": 1}"#);
+
+        assert_eq!(id, 1);
+        assert!(matches!(
+            request,
+            Request::Init(init) if init.version == 1
+                && matches!(init.format, Format::Json)
+                && matches!(init.mode, Mode::Tcp)
+                && init.slow_consumer.is_none()
+                && !init.market_messages
+        ));
+    }
+
+    #[test]
+    fn empty_types_subscribe_to_all() {
+        let (id, request) = parse(r#"{"op": "subscribe", "id": 2, "tokens": [10, 20], "types": []}"#);
+
+        assert_eq!(id, 2);
+        assert!(matches!(
+            request,
+            Request::Subscribe(subscriptions)
+                if subscriptions.iter().map(|s| s.token).eq([10, 20])
+                    && subscriptions.iter().all(|s| s.dtype.bits() == TypeFlags::ALL.bits())
+        ));
+
+        // Missing types behave the same
+        let (_, request) = parse(r#"{"op": "unsubscribe", "tokens": [10]}"#);
+
+        assert!(matches!(
+            request,
+            Request::Unsubscribe(subscriptions) if subscriptions[0].dtype.bits() == TypeFlags::ALL.bits()
+        ));
+    }
+
+    #[test]
+    fn unknown_type_is_rejected() {
+        let (id, request) = parse(r#"{"op": "subscribe", "id": 3, "tokens": [10], "types": ["depth", "ohlc"]}"#);
+
+        assert_eq!(id, 3);
+        assert!(matches!(request, Request::Invalid(ErrorCode::InvalidType)));
+    }
+
+    #[test]
+    fn unknown_op_is_rejected() {
+        let (id, request) = parse(r#"{"op": "heartbeat", "id": 4}"#);
+
+        assert_eq!(id, 4);
+        assert!(matches!(request, Request::Invalid(ErrorCode::UnknownRequest)));
+    }
+
+    #[test]
+    fn id_of_malformed_request_is_recovered() {
+        // Tokens are not a list
+        let (id, request) = parse(r#"{"op": "subscribe", "id": 5, "tokens": 10}"#);
+
+        assert_eq!(id, 5);
+        assert!(matches!(request, Request::Invalid(ErrorCode::MalformedRequest)));
+
+        // Not json at all
+        let (id, request) = parse("subscribe 10");
+
+        assert_eq!(id, 0);
+        assert!(matches!(request, Request::Invalid(ErrorCode::MalformedRequest)));
+    }
+}
@@ -1,155 +1,406 @@
 use crossbeam::queue::SegQueue;
-use std::{cell::UnsafeCell, sync::RwLock};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    Arc, Mutex, MutexGuard, PoisonError, RwLock,
+};
+
+// Slots are only released once there are this many
+const SHRINK_MIN_SLOTS: usize = 64;
+// Trailing free slots are released when less than 1 / SHRINK_RATIO of slots are live
+const SHRINK_RATIO: usize = 4;
 
 #[derive(Debug)]
 pub struct ReuseArr<T> {
-    arr: RwLock<Vec<UnsafeCell<Option<T>>>>,
+    arr: RwLock<Vec<Slot<T>>>,
     free_queue: SegQueue<usize>,
+    // Lowest index never handed out
+    next_idx: AtomicUsize,
+    // Occupied slots, reserved slots without value are not counted
+    live: AtomicUsize,
+    // Generation new slots start from, so handles of released slots stay stale
+    retired_generation: AtomicU32,
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    // Incremented on remove, so handles to previous occupant stop matching
+    // Changed only while value is locked, so generation and value are seen together
+    generation: AtomicU32,
+    // Index is in free queue
+    free: AtomicBool,
+    // Shared with threads still using entry after it is removed, freed by last of them
+    value: Mutex<Option<Arc<T>>>,
 }
 
-// Assuming safety because, Vector size can only be changed with a write lock.
-// Modifying inner elements take read lock, because one thread in threadpool only modifies one index.
-// Multiple threads are not allowed to modify inner elements.
+// Index of a slot along with generation it was reserved in
+// Handles of removed entries are rejected even after their index is reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle {
+    idx: u32,
+    generation: u32,
+}
+
+impl Handle {
+    pub fn idx(&self) -> usize {
+        self.idx as usize
+    }
+}
+
+// Generation is kept in high bits, so handle fits in a poll token
+impl From<Handle> for usize {
+    fn from(handle: Handle) -> Self {
+        ((handle.generation as usize) << 32) | handle.idx as usize
+    }
+}
+
+impl From<usize> for Handle {
+    fn from(value: usize) -> Self {
+        Self {
+            idx: value as u32,
+            generation: (value >> 32) as u32,
+        }
+    }
+}
 
-// If we require multiple threads to access same index, we will need to place rwlock inside option i.e. unsafecell<option<rwlock>>
+impl<T> Slot<T> {
+    fn new(generation: u32) -> Self {
+        Self {
+            generation: AtomicU32::new(generation),
+            free: AtomicBool::new(true),
+            value: Mutex::new(None),
+        }
+    }
 
-unsafe impl<T> Sync for ReuseArr<T> {}
-unsafe impl<T> Send for ReuseArr<T> {}
+    fn lock(&self) -> MutexGuard<'_, Option<Arc<T>>> {
+        self.value.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
 
 impl<T> ReuseArr<T> {
     pub fn new() -> Self {
-        let free_queue = SegQueue::new();
-        let arr = RwLock::new(Vec::new());
+        Self {
+            arr: RwLock::new(Vec::new()),
+            free_queue: SegQueue::new(),
+            next_idx: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            retired_generation: AtomicU32::new(0),
+        }
+    }
 
-        // Push initial indexes
-        {
-            let arr = arr.read().unwrap();
-            for i in 0..arr.capacity() {
-                free_queue.push(i);
-            }
+    pub fn reserve(&self) -> Handle {
+        // Reuse free index, otherwise take a new one
+        let idx = self
+            .free_queue
+            .pop()
+            .unwrap_or_else(|| self.next_idx.fetch_add(1, Ordering::Relaxed));
+
+        let arr = self.arr.read().unwrap();
+
+        // If index is already allocated
+        if idx < arr.len() {
+            let slot = &arr[idx];
+
+            *slot.lock() = None;
+
+            return Self::take_slot(idx, slot);
         }
 
-        Self { arr, free_queue }
-    }
+        // Drop read lock
+        drop(arr);
 
-    pub fn reserve(&self) -> usize {
-        if let Some(idx) = self.free_queue.pop() {
-            let arr = self.arr.read().unwrap();
+        // Get write lock
+        let mut arr = self.arr.write().unwrap();
 
-            // If index is already allocated
-            if idx < arr.len() {
-                let element = arr[idx].get();
+        // Indexes can be taken out of order or belong to released slots
+        self.grow(&mut arr, idx + 1);
 
-                unsafe {
-                    *element = None;
-                }
-            } else {
-                // Drop read lock
-                drop(arr);
+        Self::take_slot(idx, &arr[idx])
+    }
 
-                // Get write lock
-                let mut arr = self.arr.write().unwrap();
+    #[allow(dead_code)]
+    pub fn insert(&self, data: T) -> Handle {
+        let handle = self.reserve();
 
-                arr.push(UnsafeCell::new(None));
-            }
+        self.insert_at(data, handle);
 
-            idx
-        } else {
-            let mut arr = self.arr.write().unwrap();
-            // No free index, allocate a new one
-            let idx = arr.len();
-            arr.push(UnsafeCell::new(None));
+        handle
+    }
 
-            let size = arr.capacity();
+    // Returns false if handle is stale
+    pub fn insert_at(&self, data: T, handle: Handle) -> bool {
+        let arr = self.arr.read().unwrap();
 
-            // Push to free queue
-            for i in idx + 1..size {
-                self.free_queue.push(i);
-            }
+        let Some(mut value) = Self::slot(&arr, handle) else {
+            return false;
+        };
 
-            idx
+        let previous = value.replace(Arc::new(data));
+
+        if previous.is_none() {
+            self.live.fetch_add(1, Ordering::Relaxed);
         }
+
+        true
     }
 
-    pub fn insert(&self, data: T) -> usize {
-        if let Some(idx) = self.free_queue.pop() {
-            let arr = self.arr.read().unwrap();
+    // Returns None if slot is empty or handle is stale
+    // Entry stays alive while returned Arc is held, even if it is removed meanwhile
+    pub fn get(&self, handle: Handle) -> Option<Arc<T>> {
+        let arr = self.arr.read().unwrap();
 
-            if idx < arr.len() {
-                let element = arr[idx].get();
+        let value = Self::slot(&arr, handle)?;
 
-                unsafe {
-                    *element = Some(data);
-                }
-            } else {
-                // Drop read lock
-                drop(arr);
+        value.clone()
+    }
 
-                // Get write lock
-                let mut arr = self.arr.write().unwrap();
+    // Number of occupied slots
+    pub fn len(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
 
-                arr.push(UnsafeCell::new(Some(data)));
-            }
+    // Number of indexes ready for reuse, including ones of released slots
+    pub fn free_count(&self) -> usize {
+        self.free_queue.len()
+    }
 
-            idx
-        } else {
-            let mut arr = self.arr.write().unwrap();
+    // Call f for every occupied slot, slots without value are skipped
+    // Slots are neither added nor released meanwhile, entries inserted or removed meanwhile may be missed
+    // Each entry is taken under lock of its slot and f runs without it
+    pub fn for_each_occupied(&self, mut f: impl FnMut(Handle, &T)) {
+        let arr = self.arr.read().unwrap();
 
-            // No free index, allocate a new one
-            arr.push(UnsafeCell::new(Some(data)));
-            let idx = arr.len();
+        for (idx, slot) in arr.iter().enumerate() {
+            let entry = {
+                let value = slot.lock();
 
-            let size = arr.capacity();
+                value.clone().map(|value| (Self::handle(idx, slot), value))
+            };
 
-            // Push to free queue
-            for i in idx..size {
-                self.free_queue.push(i);
+            if let Some((handle, value)) = entry {
+                f(handle, &value);
             }
-
-            idx
         }
     }
 
-    pub fn insert_at(&self, data: T, idx: usize) -> usize {
+    // Handles of occupied slots at time of call
+    // Entries removed later are rejected by get
+    pub fn snapshot(&self) -> Vec<Handle> {
+        let mut handles = Vec::with_capacity(self.len());
+
+        self.for_each_occupied(|handle, _| handles.push(handle));
+
+        handles
+    }
+
+    // Free slot of handle, it can be reused only by a new handle
+    // Returns None without freeing anything if handle is stale
+    pub fn remove(&self, handle: Handle) -> Option<Arc<T>> {
         let arr = self.arr.read().unwrap();
 
-        if idx < arr.len() {
-            let element = arr[idx].get();
+        let slot = arr.get(handle.idx())?;
+        let mut value = slot.lock();
+
+        // Only one remove of a handle can win
+        if slot
+            .generation
+            .compare_exchange(
+                handle.generation,
+                handle.generation.wrapping_add(1),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return None;
+        }
+
+        let removed = value.take();
+
+        if removed.is_some() {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        slot.free.store(true, Ordering::Release);
+
+        // Write lock stalls every get, so it is only taken if trailing slot can be released
+        let shrink = arr.len() >= SHRINK_MIN_SLOTS
+            && self.len() * SHRINK_RATIO < arr.len()
+            && arr.last().is_some_and(|slot| slot.free.load(Ordering::Acquire));
+
+        drop(value);
+        drop(arr);
+
+        self.free_queue.push(handle.idx());
+
+        if shrink {
+            self.shrink();
+        }
+
+        removed
+    }
 
-            unsafe {
-                *element = Some(data);
+    // Release free slots at end of vector
+    // Their indexes stay in free queue and get slots again once reused
+    pub fn shrink(&self) {
+        let mut arr = self.arr.write().unwrap();
+        let slots = arr.len();
+
+        while let Some(slot) = arr.last() {
+            if !slot.free.load(Ordering::Acquire) {
+                break;
             }
-        } else {
-            drop(arr);
 
-            let mut arr = self.arr.write().unwrap();
-            arr.push(UnsafeCell::new(Some(data)));
+            let generation = slot.generation.load(Ordering::Acquire);
+            self.retired_generation.fetch_max(generation, Ordering::Relaxed);
+
+            arr.pop();
         }
 
-        idx
+        if arr.len() < slots {
+            arr.shrink_to_fit();
+        }
     }
 
-    pub fn get(&self, idx: usize) -> &Option<T> {
-        let arr = self.arr.read().unwrap();
+    // Add slots till vector has size slots, new slots are marked free until taken
+    fn grow(&self, arr: &mut Vec<Slot<T>>, size: usize) {
+        let generation = self.retired_generation.load(Ordering::Relaxed);
+
+        while arr.len() < size {
+            arr.push(Slot::new(generation));
+        }
+    }
 
-        let element = arr[idx].get();
+    fn take_slot(idx: usize, slot: &Slot<T>) -> Handle {
+        slot.free.store(false, Ordering::Release);
 
-        unsafe { &*element }
+        Self::handle(idx, slot)
     }
 
-    pub fn get_mut(&self, idx: usize) -> &mut Option<T> {
-        let arr = self.arr.read().unwrap();
+    fn handle(idx: usize, slot: &Slot<T>) -> Handle {
+        Handle {
+            idx: idx as u32,
+            generation: slot.generation.load(Ordering::Acquire),
+        }
+    }
 
-        let element = arr[idx].get();
+    // Locked value of slot of handle, None if handle is stale
+    fn slot(arr: &[Slot<T>], handle: Handle) -> Option<MutexGuard<'_, Option<Arc<T>>>> {
+        let slot = arr.get(handle.idx())?;
+        let value = slot.lock();
 
-        unsafe { &mut *element }
+        (slot.generation.load(Ordering::Acquire) == handle.generation).then_some(value)
     }
+}
 
-    pub fn remove(&self, idx: usize) -> Option<T> {
-        self.free_queue.push(idx);
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
 
-        let arr = self.arr.read().unwrap();
+    use super::{ReuseArr, SHRINK_MIN_SLOTS};
+
+    #[test]
+    fn stale_handles_are_rejected() {
+        let arr = ReuseArr::new();
 
-        unsafe { (*arr[idx].get()).take() }
+        let first = arr.insert(1);
+        assert_eq!(arr.remove(first).as_deref(), Some(&1));
+
+        // Index is reused with a new generation
+        let second = arr.insert(2);
+        assert_eq!(first.idx(), second.idx());
+
+        assert_eq!(arr.get(first), None);
+        assert_eq!(arr.remove(first), None);
+        assert!(!arr.insert_at(3, first));
+        assert_eq!(arr.get(second).as_deref(), Some(&2));
+    }
+
+    #[test]
+    fn iteration_skips_reserved_and_free_slots() {
+        let arr = ReuseArr::new();
+
+        // Like listener slots, reserved without value
+        arr.reserve();
+        arr.reserve();
+
+        let handles: Vec<_> = (0..4).map(|i| arr.insert(i)).collect();
+        arr.remove(handles[1]);
+
+        let mut values = Vec::new();
+        arr.for_each_occupied(|_, value| values.push(*value));
+
+        assert_eq!(values, [0, 2, 3]);
+        assert_eq!(arr.snapshot(), [handles[0], handles[2], handles[3]]);
+        assert_eq!(arr.len(), 3);
+        assert_eq!(arr.free_count(), 1);
+    }
+
+    #[test]
+    fn trailing_free_slots_are_released() {
+        let arr = ReuseArr::new();
+
+        let kept = arr.insert(0);
+        let handles: Vec<_> = (1..SHRINK_MIN_SLOTS * 2).map(|i| arr.insert(i)).collect();
+
+        for handle in &handles {
+            arr.remove(*handle);
+        }
+
+        assert!(arr.arr.read().unwrap().len() < SHRINK_MIN_SLOTS);
+        assert_eq!(arr.get(kept).as_deref(), Some(&0));
+
+        // Released indexes are reused without reviving old handles
+        let reused: Vec<_> = (0..handles.len()).map(|i| arr.insert(i)).collect();
+
+        for handle in &handles {
+            assert_eq!(arr.get(*handle), None);
+        }
+        assert_eq!(arr.len(), reused.len() + 1);
+    }
+
+    #[test]
+    fn iteration_is_consistent_with_concurrent_changes() {
+        let arr = ReuseArr::new();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut handles = VecDeque::new();
+
+                for i in 0..20_000 {
+                    let handle = arr.reserve();
+                    arr.insert_at(usize::from(handle), handle);
+                    handles.push_back(handle);
+
+                    if handles.len() > SHRINK_MIN_SLOTS * 2 {
+                        arr.remove(handles.pop_front().unwrap());
+                    }
+
+                    // Emptying the array releases slots while they are iterated
+                    if i % 1000 == 999 {
+                        for handle in handles.drain(..) {
+                            arr.remove(handle);
+                        }
+                    }
+                }
+
+                done.store(true, Ordering::Release);
+            });
+
+            // Entries are only ever seen with handle they were inserted at
+            while !done.load(Ordering::Acquire) {
+                arr.for_each_occupied(|handle, value| assert_eq!(*value, usize::from(handle)));
+
+                for handle in arr.snapshot() {
+                    if let Some(value) = arr.get(handle) {
+                        assert_eq!(*value, usize::from(handle));
+                    }
+                }
+            }
+        });
     }
 }
@@ -4,15 +4,68 @@ use crate::globals::SETTINGS;
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
+    #[serde(rename = "type", default)]
+    pub role: Role,
+    #[serde(alias = "redistributor_address")]
     pub distributor_address: Option<String>,
     pub kafka_address: Option<String>,
     pub kafka_topic: Option<String>,
     pub kafka_partition: Vec<usize>,
+    #[serde(default)]
+    pub kafka_offset: KafkaOffset,
     pub tcp_address: String,
     pub ws_address: String,
     pub mode: Mode,
     pub interface_ip: String,
     pub udp_multicast_address: String,
+    // Local address for sending market data to udp clients
+    #[serde(default = "default_udp_output_address")]
+    pub udp_output_address: String,
+    // Default policy for clients which do not choose one at init
+    #[serde(default)]
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    // Bytes of pending output after which policy applies
+    #[serde(default = "default_slow_consumer_threshold")]
+    pub slow_consumer_threshold: usize,
+    // How threadpool dispatchers wait for work
+    #[serde(default)]
+    pub wait_strategy: WaitStrategy,
+    // Core pinning and priority of threads
+    #[serde(default)]
+    pub threads: ThreadSettings,
+    // Exchange tokens data is kept for, others are rejected
+    #[serde(default)]
+    pub tokens: TokenSettings,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TokenSettings {
+    // Token ranges as [start, end), end is exclusive
+    #[serde(default)]
+    pub ranges: Vec<[usize; 2]>,
+    // File with a token at start of each line, usually contract master csv
+    pub contract_master: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ThreadSettings {
+    #[serde(default)]
+    pub feed_input: ThreadConfig,
+    #[serde(default)]
+    pub client_input: ThreadConfig,
+    #[serde(default)]
+    pub feed_output: ThreadConfig,
+    #[serde(default)]
+    pub client_output: ThreadConfig,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ThreadConfig {
+    // Cores threads may run on, unpinned if empty
+    #[serde(default)]
+    pub cores: Vec<usize>,
+    // Real time fifo priority, normal scheduling if missing
+    pub priority: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Clone, Default, Copy)]
@@ -24,6 +77,77 @@ pub enum Mode {
     Kafka,
 }
 
+#[derive(Debug, Deserialize, Clone, Default, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Distributor,
+    // Consumes feed from an upstream distributor
+    Redistributor,
+}
+
+// What happens to updates of a client whose pending output passed threshold
+#[derive(Debug, Deserialize, Clone, Default, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowConsumerPolicy {
+    // Keep only latest update per token and type until output drains
+    #[default]
+    Conflate,
+    // Drop depth first, then touch line, then mini touch line as output grows
+    Drop,
+    Disconnect,
+}
+
+// Trade off between dispatch latency and cpu burnt while idle
+#[derive(Debug, Deserialize, Clone, Default, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitStrategy {
+    // Lowest latency, keeps a core busy even when idle
+    BusySpin,
+    #[default]
+    SpinYield,
+    // Sleep once idle for a while, woken up when work is pushed
+    Park,
+}
+
+impl WaitStrategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WaitStrategy::BusySpin => "busy_spin",
+            WaitStrategy::SpinYield => "spin_yield",
+            WaitStrategy::Park => "park",
+        }
+    }
+}
+
+// Where kafka consumption starts for each partition
+#[derive(Debug, Deserialize, Clone, Default, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaOffset {
+    Earliest,
+    #[default]
+    Latest,
+    // Unix timestamp in milliseconds
+    Timestamp(i64),
+}
+
+impl Default for TokenSettings {
+    fn default() -> Self {
+        Self {
+            ranges: vec![[0, 35000]],
+            contract_master: None,
+        }
+    }
+}
+
+fn default_udp_output_address() -> String {
+    "0.0.0.0:0".to_string()
+}
+
+fn default_slow_consumer_threshold() -> usize {
+    1024 * 1024
+}
+
 pub fn init(path: &String) {
     let data = std::fs::read_to_string(path).unwrap();
     let settings: Settings = serde_json::from_str(&data).unwrap();
@@ -1,16 +1,309 @@
-use super::client_profile::ClientProfile;
+use super::{
+    client_profile::{ClientSubscription, TypeFlags},
+    reuse_array::Handle,
+    settings::Mode,
+};
 
-pub struct Subscription<'a> {
-    pub all_clients: Vec<&'a ClientProfile>,
-    pub tcp_clients: Vec<&'a ClientProfile>,
+// Subscribers of a single token
+#[derive(Debug)]
+pub struct Subscription {
+    pub all_clients: Vec<Subscriber>,
+    pub tcp_clients: Vec<Subscriber>,
     total_udp_count: usize,
     total_count: usize,
     udp_type_count: TypeCount,
     tcp_type_count: TypeCount,
 }
 
+// Change of subscriptions to forward to upstream distributor
+#[derive(Debug, Clone, Copy)]
+pub enum UpstreamRequest {
+    Subscribe(ClientSubscription),
+    Unsubscribe(ClientSubscription),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Subscriber {
+    pub client: Handle,
+    pub dtype: TypeFlags,
+}
+
+#[derive(Debug)]
 pub struct TypeCount {
     pub depth_count: usize,
     pub touch_line_count: usize,
     pub mini_touch_line_count: usize,
 }
+
+impl Subscription {
+    pub const fn new() -> Self {
+        Self {
+            all_clients: Vec::new(),
+            tcp_clients: Vec::new(),
+            total_udp_count: 0,
+            total_count: 0,
+            udp_type_count: TypeCount::new(),
+            tcp_type_count: TypeCount::new(),
+        }
+    }
+
+    // Add types for client
+    // Returns types which had no subscriber before
+    pub fn subscribe(&mut self, client: Handle, dtype: TypeFlags, mode: Mode) -> TypeFlags {
+        let wanted = self.wanted_types();
+
+        let added = match self
+            .all_clients
+            .iter_mut()
+            .find(|subscriber| subscriber.client == client)
+        {
+            Some(subscriber) => {
+                let added = dtype.difference(subscriber.dtype);
+                subscriber.dtype |= dtype;
+                added
+            }
+            None => {
+                self.all_clients.push(Subscriber { client, dtype });
+                self.total_count += 1;
+
+                if is_udp(mode) {
+                    self.total_udp_count += 1;
+                }
+
+                dtype
+            }
+        };
+
+        if !is_udp(mode) {
+            match self
+                .tcp_clients
+                .iter_mut()
+                .find(|subscriber| subscriber.client == client)
+            {
+                Some(subscriber) => subscriber.dtype |= dtype,
+                None => self.tcp_clients.push(Subscriber { client, dtype }),
+            }
+        }
+
+        self.type_count_mut(mode).add(added);
+
+        self.wanted_types().difference(wanted)
+    }
+
+    // Remove types for client, client is removed when no types are left
+    // Returns types which have no subscriber left
+    pub fn unsubscribe(&mut self, client: Handle, dtype: TypeFlags, mode: Mode) -> TypeFlags {
+        let wanted = self.wanted_types();
+
+        let Some(pos) = self
+            .all_clients
+            .iter()
+            .position(|subscriber| subscriber.client == client)
+        else {
+            return TypeFlags::empty();
+        };
+
+        let subscriber = &mut self.all_clients[pos];
+        let removed = subscriber.dtype.intersection(dtype);
+        subscriber.dtype.remove(dtype);
+
+        let empty = subscriber.dtype.is_empty();
+
+        if empty {
+            self.all_clients.swap_remove(pos);
+            self.total_count -= 1;
+
+            if is_udp(mode) {
+                self.total_udp_count -= 1;
+            }
+        }
+
+        if let Some(pos) = self
+            .tcp_clients
+            .iter()
+            .position(|subscriber| subscriber.client == client)
+        {
+            if empty {
+                self.tcp_clients.swap_remove(pos);
+            } else {
+                self.tcp_clients[pos].dtype.remove(dtype);
+            }
+        }
+
+        self.type_count_mut(mode).remove(removed);
+
+        wanted.difference(self.wanted_types())
+    }
+
+    // Remove client completely, used on disconnection
+    pub fn remove_client(&mut self, client: Handle, mode: Mode) -> TypeFlags {
+        self.unsubscribe(client, TypeFlags::ALL, mode)
+    }
+
+    // Move client between udp and tcp delivery
+    pub fn switch_mode(&mut self, client: Handle, from: Mode, to: Mode) {
+        if is_udp(from) == is_udp(to) {
+            return;
+        }
+
+        let Some(subscriber) = self
+            .all_clients
+            .iter()
+            .find(|subscriber| subscriber.client == client)
+            .copied()
+        else {
+            return;
+        };
+
+        self.type_count_mut(from).remove(subscriber.dtype);
+        self.type_count_mut(to).add(subscriber.dtype);
+
+        if is_udp(to) {
+            self.total_udp_count += 1;
+            self.tcp_clients.retain(|subscriber| subscriber.client != client);
+        } else {
+            self.total_udp_count -= 1;
+            self.tcp_clients.push(subscriber);
+        }
+    }
+
+    pub fn total_udp_count(&self) -> usize {
+        self.total_udp_count
+    }
+
+    // Types with at least one subscriber
+    pub fn wanted_types(&self) -> TypeFlags {
+        self.udp_type_count.wanted_types() | self.tcp_type_count.wanted_types()
+    }
+
+    pub fn is_wanted(&self, dtype: TypeFlags) -> bool {
+        self.wanted_types().intersects(dtype)
+    }
+
+    fn type_count_mut(&mut self, mode: Mode) -> &mut TypeCount {
+        if is_udp(mode) {
+            &mut self.udp_type_count
+        } else {
+            &mut self.tcp_type_count
+        }
+    }
+}
+
+impl TypeCount {
+    pub const fn new() -> Self {
+        Self {
+            depth_count: 0,
+            touch_line_count: 0,
+            mini_touch_line_count: 0,
+        }
+    }
+
+    pub fn add(&mut self, dtype: TypeFlags) {
+        if dtype.contains(TypeFlags::DEPTH) {
+            self.depth_count += 1;
+        }
+        if dtype.contains(TypeFlags::TOUCH_LINE) {
+            self.touch_line_count += 1;
+        }
+        if dtype.contains(TypeFlags::MINI_TOUCH_LINE) {
+            self.mini_touch_line_count += 1;
+        }
+    }
+
+    pub fn remove(&mut self, dtype: TypeFlags) {
+        if dtype.contains(TypeFlags::DEPTH) {
+            self.depth_count -= 1;
+        }
+        if dtype.contains(TypeFlags::TOUCH_LINE) {
+            self.touch_line_count -= 1;
+        }
+        if dtype.contains(TypeFlags::MINI_TOUCH_LINE) {
+            self.mini_touch_line_count -= 1;
+        }
+    }
+
+    pub fn wanted_types(&self) -> TypeFlags {
+        let mut dtype = TypeFlags::empty();
+
+        dtype.set(TypeFlags::DEPTH, self.depth_count > 0);
+        dtype.set(TypeFlags::TOUCH_LINE, self.touch_line_count > 0);
+        dtype.set(TypeFlags::MINI_TOUCH_LINE, self.mini_touch_line_count > 0);
+
+        dtype
+    }
+}
+
+fn is_udp(mode: Mode) -> bool {
+    matches!(mode, Mode::Udp)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{client_profile::TypeFlags, reuse_array::Handle, settings::Mode};
+
+    use super::Subscription;
+
+    fn tcp_clients(subscription: &Subscription) -> Vec<(Handle, u8)> {
+        subscription
+            .tcp_clients
+            .iter()
+            .map(|subscriber| (subscriber.client, subscriber.dtype.bits()))
+            .collect()
+    }
+
+    #[test]
+    fn partial_unsubscribe_keeps_remaining_types() {
+        let (a, b) = (Handle::from(2), Handle::from(3));
+        let mut subscription = Subscription::new();
+
+        let added = subscription.subscribe(a, TypeFlags::DEPTH | TypeFlags::TOUCH_LINE, Mode::Tcp);
+        assert_eq!(added.bits(), (TypeFlags::DEPTH | TypeFlags::TOUCH_LINE).bits());
+
+        // Already wanted by a
+        assert!(subscription.subscribe(b, TypeFlags::TOUCH_LINE, Mode::Tcp).is_empty());
+        assert_eq!(tcp_clients(&subscription).len(), 2);
+
+        // Touch line is still wanted by b
+        assert!(subscription.unsubscribe(a, TypeFlags::TOUCH_LINE, Mode::Tcp).is_empty());
+        assert!(tcp_clients(&subscription).contains(&(a, TypeFlags::DEPTH.bits())));
+
+        let removed = subscription.unsubscribe(a, TypeFlags::DEPTH, Mode::Tcp);
+        assert_eq!(removed.bits(), TypeFlags::DEPTH.bits());
+        assert_eq!(tcp_clients(&subscription), [(b, TypeFlags::TOUCH_LINE.bits())]);
+        assert_eq!(subscription.wanted_types().bits(), TypeFlags::TOUCH_LINE.bits());
+        assert_eq!(subscription.total_udp_count(), 0);
+    }
+
+    #[test]
+    fn udp_switch_and_disconnect_update_counts() {
+        let (a, b) = (Handle::from(2), Handle::from(3));
+        let mut subscription = Subscription::new();
+
+        subscription.subscribe(a, TypeFlags::DEPTH, Mode::Tcp);
+        subscription.subscribe(b, TypeFlags::MINI_TOUCH_LINE, Mode::Udp);
+        assert_eq!(subscription.total_udp_count(), 1);
+        assert_eq!(tcp_clients(&subscription), [(a, TypeFlags::DEPTH.bits())]);
+
+        // Types stay wanted, only delivery changes
+        subscription.switch_mode(a, Mode::Tcp, Mode::Udp);
+        assert_eq!(subscription.total_udp_count(), 2);
+        assert!(tcp_clients(&subscription).is_empty());
+        assert_eq!(
+            subscription.wanted_types().bits(),
+            (TypeFlags::DEPTH | TypeFlags::MINI_TOUCH_LINE).bits()
+        );
+
+        // Later subscriptions of a udp client stay out of tcp clients
+        subscription.subscribe(a, TypeFlags::TOUCH_LINE, Mode::Udp);
+        assert!(tcp_clients(&subscription).is_empty());
+
+        let removed = subscription.remove_client(a, Mode::Udp);
+        assert_eq!(removed.bits(), (TypeFlags::DEPTH | TypeFlags::TOUCH_LINE).bits());
+        assert_eq!(subscription.total_udp_count(), 1);
+
+        subscription.remove_client(b, Mode::Udp);
+        assert_eq!(subscription.total_udp_count(), 0);
+        assert!(subscription.wanted_types().is_empty());
+        assert!(subscription.all_clients.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs;
+
+use super::settings::TokenSettings;
+
+// Exchange tokens data is kept for, each mapped to a dense slot
+// Slots index per token globals, so sparse tokens do not waste memory
+#[derive(Debug)]
+pub struct TokenUniverse {
+    // Sorted and disjoint, adjacent ranges are merged
+    ranges: Vec<TokenRange>,
+    slots: usize,
+}
+
+#[derive(Debug)]
+struct TokenRange {
+    start: usize,
+    // Exclusive
+    end: usize,
+    // Slot of start token
+    first_slot: usize,
+}
+
+impl TokenUniverse {
+    // Ranges are [start, end), they may overlap or come in any order
+    pub fn new(ranges: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        let mut sorted: Vec<_> = ranges.into_iter().filter(|(start, end)| start < end).collect();
+        sorted.sort_unstable();
+
+        let mut merged: Vec<TokenRange> = Vec::with_capacity(sorted.len());
+        let mut slots = 0;
+
+        for (start, end) in sorted {
+            match merged.last_mut() {
+                Some(last) if start <= last.end => {
+                    if end > last.end {
+                        slots += end - last.end;
+                        last.end = end;
+                    }
+                }
+                _ => {
+                    merged.push(TokenRange {
+                        start,
+                        end,
+                        first_slot: slots,
+                    });
+                    slots += end - start;
+                }
+            }
+        }
+
+        Self { ranges: merged, slots }
+    }
+
+    // Union of configured ranges and tokens of contract master
+    pub fn from_settings(settings: &TokenSettings) -> Self {
+        let mut ranges: Vec<_> = settings.ranges.iter().map(|&[start, end]| (start, end)).collect();
+
+        if let Some(path) = &settings.contract_master {
+            let data = fs::read_to_string(path).unwrap();
+
+            ranges.extend(parse_contract_master(&data).map(|token| (token, token + 1)));
+        }
+
+        Self::new(ranges)
+    }
+
+    // Number of slots
+    pub fn len(&self) -> usize {
+        self.slots
+    }
+
+    // Returns None if token is not part of universe
+    pub fn slot(&self, token: usize) -> Option<usize> {
+        let idx = self.ranges.partition_point(|range| range.end <= token);
+        let range = self.ranges.get(idx)?;
+
+        (range.start <= token).then(|| range.first_slot + token - range.start)
+    }
+
+    // Exchange token of slot, slot must be below len
+    pub fn token(&self, slot: usize) -> usize {
+        let idx = self.ranges.partition_point(|range| range.first_slot <= slot) - 1;
+        let range = &self.ranges[idx];
+
+        range.start + slot - range.first_slot
+    }
+}
+
+// Token is the first comma separated field of a line
+// Lines not starting with a token, like headers, are skipped
+fn parse_contract_master(data: &str) -> impl Iterator<Item = usize> + '_ {
+    data.lines()
+        .filter_map(|line| line.split(',').next()?.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_contract_master, TokenUniverse};
+
+    #[test]
+    fn sparse_tokens_map_to_dense_slots() {
+        let universe = TokenUniverse::new([(50_000, 50_003), (10, 12), (11, 13), (13, 14), (90_000_000, 90_000_001)]);
+
+        assert_eq!(universe.len(), 8);
+
+        let tokens = [10, 11, 12, 13, 50_000, 50_001, 50_002, 90_000_000];
+
+        for (slot, token) in tokens.into_iter().enumerate() {
+            assert_eq!(universe.slot(token), Some(slot));
+            assert_eq!(universe.token(slot), token);
+        }
+
+        for token in [0, 9, 14, 49_999, 50_003, 90_000_001, usize::MAX] {
+            assert_eq!(universe.slot(token), None);
+        }
+    }
+
+    #[test]
+    fn contract_master_headers_are_skipped() {
+        let tokens: Vec<_> = parse_contract_master("token,symbol\n35001,NIFTY\n\n 42 ,BANKNIFTY\n").collect();
+
+        assert_eq!(tokens, [35001, 42]);
+    }
+}
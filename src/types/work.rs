@@ -1,15 +1,18 @@
 use crate::threadpool::WorkTrait;
 
-use super::client_profile::ClientProfile;
+use super::reuse_array::Handle;
 
+// Work of a single client, run in order of scheduling
 #[derive(Debug, Clone, Copy)]
 pub struct ClientWork {
-    pub work_type: WorkType,
-    pub client_profile: &'static ClientProfile,
+    pub client: Handle,
+    pub processing_fn: fn(&Self),
 }
 
 impl WorkTrait for ClientWork {
-    fn do_work(&self) {}
+    fn do_work(&self) {
+        (self.processing_fn)(self);
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -26,7 +29,9 @@ impl WorkTrait for FeedWork {
 
 #[derive(Debug, Clone, Copy)]
 pub enum WorkType {
+    #[allow(dead_code)]
     TokenWiseLatest(usize),
+    // Slot of token in token universe
     TokenWise(usize),
     MarketMessage,
 }
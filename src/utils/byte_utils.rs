@@ -7,10 +7,16 @@ pub fn bytes_to_struct<T>(s: &[u8]) -> T {
     unsafe {
         let src = s.as_ptr() as *const T;
 
-        std::ptr::read(src)
+        std::ptr::read_unaligned(src)
     }
 }
 
+// Read struct from buffer holding exactly its bytes
+// Returns None if sizes differ
+pub fn parse_struct<T: Copy>(buffer: &[u8]) -> Option<T> {
+    (buffer.len() == mem::size_of::<T>()).then(|| bytes_to_struct(buffer))
+}
+
 pub fn struct_to_bytes<T: Copy>(s: &T, buffer: &mut [u8]) {
     unsafe {
         let mut size = std::mem::size_of::<T>();
@@ -50,6 +56,7 @@ pub fn bytes_to_partial_struct<T>(s: &mut T, buffer: &[u8]) {
     };
 }
 
+#[allow(dead_code)]
 pub fn bytes_to_struct_mut<T>(buf: &mut [u8]) -> &mut T {
     unsafe { &mut *(buf.as_mut_ptr() as *mut T) }
 }
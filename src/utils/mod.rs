@@ -1,2 +1,3 @@
 pub mod byte_utils;
 pub mod error_utils;
+pub mod thread_utils;
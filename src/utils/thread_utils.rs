@@ -0,0 +1,143 @@
+use std::{
+    cell::Cell,
+    io,
+    thread::{self, JoinHandle},
+};
+
+use crate::types::settings::{self, ThreadConfig};
+
+// Kinds of threads which can be pinned and prioritized separately
+#[derive(Debug, Clone, Copy)]
+pub enum ThreadRole {
+    // Receives feed from exchange, kafka or upstream distributor
+    FeedInput,
+    // Polls client connections
+    ClientInput,
+    // Dispatches feed to clients
+    FeedOutput,
+    // Writes queued output to client sockets
+    ClientOutput,
+}
+
+thread_local! {
+    // Set once current pool thread got configured
+    static CONFIGURED: Cell<bool> = const { Cell::new(false) };
+}
+
+impl ThreadRole {
+    // Linux shows only first 15 bytes of thread names
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadRole::FeedInput => "feed-in",
+            ThreadRole::ClientInput => "client-in",
+            ThreadRole::FeedOutput => "feed-out",
+            ThreadRole::ClientOutput => "client-out",
+        }
+    }
+
+    fn config(&self) -> &'static ThreadConfig {
+        let threads = &settings::get().threads;
+
+        match self {
+            ThreadRole::FeedInput => &threads.feed_input,
+            ThreadRole::ClientInput => &threads.client_input,
+            ThreadRole::FeedOutput => &threads.feed_output,
+            ThreadRole::ClientOutput => &threads.client_output,
+        }
+    }
+}
+
+// Spawn thread of role, named after it
+pub fn spawn<F, T>(role: ThreadRole, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_named(role.name().to_string(), role, f)
+}
+
+// Spawn thread configured for role
+pub fn spawn_named<F, T>(name: String, role: ThreadRole, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            configure(role);
+            f()
+        })
+        .unwrap()
+}
+
+// Configure current thread for role unless done already
+// Used by pool threads, which are not spawned by us
+pub fn configure_once(role: ThreadRole) {
+    if !CONFIGURED.replace(true) {
+        configure(role);
+    }
+}
+
+// Pin current thread to cores of role and set its priority
+// Failures are reported and thread keeps running unpinned
+fn configure(role: ThreadRole) {
+    let config = role.config();
+
+    if !config.cores.is_empty() {
+        if let Err(e) = set_affinity(&config.cores) {
+            println!("Failed to pin {} thread to {:?}: {}", role.name(), config.cores, e);
+        }
+    }
+
+    if let Some(priority) = config.priority {
+        if let Err(e) = set_realtime_priority(priority) {
+            println!("Failed to set priority {} for {} thread: {}", priority, role.name(), e);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cores: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, zeroed is an empty set
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+
+    for &core in cores {
+        if core >= libc::CPU_SETSIZE as usize {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        // SAFETY: core is within set
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+
+    // SAFETY: pid 0 is calling thread, set outlives call
+    match unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// Switch current thread to fifo scheduling, usually requires CAP_SYS_NICE
+#[cfg(target_os = "linux")]
+fn set_realtime_priority(priority: i32) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+
+    // SAFETY: param outlives call
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_realtime_priority(_: i32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}